@@ -0,0 +1,145 @@
+use std::{collections::HashMap, io::ErrorKind, process::Stdio, sync::Arc, time::Duration};
+
+use log::{debug, warn};
+use tokio::{
+	process::Command,
+	sync::{watch, RwLock},
+	time::timeout,
+};
+
+use crate::{config::ConfigProvider, packets::RunCommandItem, KdeConnectError, Result};
+
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct CommandOutput {
+	// None if the command was killed by a signal
+	pub status: Option<i32>,
+	pub stdout: Vec<u8>,
+	pub stderr: Vec<u8>,
+}
+
+impl CommandOutput {
+	pub fn success(&self) -> bool {
+		self.status == Some(0)
+	}
+}
+
+#[async_trait::async_trait]
+pub trait CommandExecutor {
+	// the command must be killed if it takes longer than cmd_timeout
+	async fn execute(&self, command: &str, cmd_timeout: Duration) -> Result<CommandOutput>;
+}
+
+// runs commands with `sh -c`
+pub struct ShellCommandExecutor;
+
+#[async_trait::async_trait]
+impl CommandExecutor for ShellCommandExecutor {
+	async fn execute(&self, command: &str, cmd_timeout: Duration) -> Result<CommandOutput> {
+		let child = Command::new("sh")
+			.arg("-c")
+			.arg(command)
+			.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.kill_on_drop(true)
+			.spawn()?;
+		// dropping the future on timeout kills the child
+		let output = timeout(cmd_timeout, child.wait_with_output())
+			.await
+			.map_err(|_| KdeConnectError::CommandTimedOut)??;
+		Ok(CommandOutput {
+			status: output.status.code(),
+			stdout: output.stdout,
+			stderr: output.stderr,
+		})
+	}
+}
+
+// commands that paired devices are allowed to run on this device
+pub struct CommandRegistry {
+	config: Arc<dyn ConfigProvider + Sync + Send>,
+	commands: RwLock<HashMap<String, RunCommandItem>>,
+	executor: RwLock<Arc<dyn CommandExecutor + Sync + Send>>,
+	timeout: RwLock<Duration>,
+	changed: watch::Sender<()>,
+}
+
+impl CommandRegistry {
+	// fails if the stored commands can't be read, so they aren't replaced by the next change
+	pub async fn new(config: Arc<dyn ConfigProvider + Sync + Send>) -> Result<Self> {
+		let commands = match config.retrieve_commands().await {
+			Ok(commands) => commands,
+			Err(KdeConnectError::Io(err)) if err.kind() == ErrorKind::NotFound => {
+				debug!("no stored commands");
+				HashMap::new()
+			}
+			Err(err) => return Err(err),
+		};
+		Ok(Self {
+			config,
+			commands: RwLock::new(commands),
+			executor: RwLock::new(Arc::new(ShellCommandExecutor)),
+			timeout: RwLock::new(DEFAULT_COMMAND_TIMEOUT),
+			changed: watch::channel(()).0,
+		})
+	}
+
+	pub async fn set_executor(&self, executor: Arc<dyn CommandExecutor + Sync + Send>) {
+		*self.executor.write().await = executor;
+	}
+
+	pub async fn set_timeout(&self, cmd_timeout: Duration) {
+		*self.timeout.write().await = cmd_timeout;
+	}
+
+	pub async fn list(&self) -> HashMap<String, RunCommandItem> {
+		self.commands.read().await.clone()
+	}
+
+	// returns the id of the new command
+	pub async fn add(&self, name: String, command: String) -> Result<String> {
+		let id = format!("{:032x}", rand::random::<u128>());
+		let mut commands = self.commands.write().await;
+		commands.insert(id.clone(), RunCommandItem { name, command });
+		self.config.store_commands(&commands).await?;
+		self.changed.send_replace(());
+		Ok(id)
+	}
+
+	pub async fn remove(&self, id: &str) -> Result<Option<RunCommandItem>> {
+		let mut commands = self.commands.write().await;
+		let removed = commands.remove(id);
+		if removed.is_some() {
+			self.config.store_commands(&commands).await?;
+			self.changed.send_replace(());
+		}
+		Ok(removed)
+	}
+
+	pub async fn run(&self, id: &str) -> Result<CommandOutput> {
+		let command = self
+			.commands
+			.read()
+			.await
+			.get(id)
+			.ok_or(KdeConnectError::UnknownCommand)?
+			.command
+			.clone();
+		let executor = self.executor.read().await.clone();
+		let cmd_timeout = *self.timeout.read().await;
+		debug!("running command {:?}: {:?}", id, command);
+		let output = executor.execute(&command, cmd_timeout).await;
+		if let Ok(output) = output.as_ref()
+			&& !output.success()
+		{
+			warn!("command {:?} exited with {:?}", id, output.status);
+		}
+		output
+	}
+
+	pub(crate) fn subscribe(&self) -> watch::Receiver<()> {
+		self.changed.subscribe()
+	}
+}
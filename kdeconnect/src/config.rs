@@ -1,8 +1,8 @@
-use crate::{device::DeviceConfig, KdeConnectError, Result};
+use crate::{device::DeviceConfig, packets::RunCommandItem, KdeConnectError, Result};
 
 use async_trait::async_trait;
 use serde_json as json;
-use std::path::PathBuf;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
 use tokio::{
 	fs::{create_dir_all, read_dir, File},
 	io::AsyncWriteExt,
@@ -16,6 +16,45 @@ pub trait ConfigProvider {
 	async fn retrieve_server_cert(&self) -> Result<Vec<u8>>;
 	async fn store_device_config(&self, config: &DeviceConfig) -> Result<()>;
 	async fn retrieve_device_config(&self, id: &str) -> Result<DeviceConfig>;
+	// paired devices are only reconnected to if the provider can list them
+	async fn retrieve_all_device_configs(&self) -> Result<Vec<DeviceConfig>> {
+		Ok(Vec::new())
+	}
+	// providers that don't persist these only keep them until the process exits
+	async fn store_commands(&self, _commands: &HashMap<String, RunCommandItem>) -> Result<()> {
+		Ok(())
+	}
+	async fn retrieve_commands(&self) -> Result<HashMap<String, RunCommandItem>> {
+		Ok(HashMap::new())
+	}
+	async fn store_custom_devices(&self, _devices: &[SocketAddr]) -> Result<()> {
+		Ok(())
+	}
+	async fn retrieve_custom_devices(&self) -> Result<Vec<SocketAddr>> {
+		Ok(Vec::new())
+	}
+}
+
+// names of the files and folders FsConfig keeps inside its path
+#[derive(Clone, Debug)]
+pub struct FsConfigNames {
+	pub cert: String,
+	pub keypair: String,
+	pub devices: String,
+	pub commands: String,
+	pub custom_devices: String,
+}
+
+impl Default for FsConfigNames {
+	fn default() -> Self {
+		Self {
+			cert: "server_cert".into(),
+			keypair: "server_keypair".into(),
+			devices: "devices".into(),
+			commands: "commands".into(),
+			custom_devices: "custom_devices".into(),
+		}
+	}
 }
 
 pub struct FsConfig {
@@ -23,44 +62,24 @@ pub struct FsConfig {
 	pub device_path: PathBuf,
 	pub cert_path: PathBuf,
 	pub keypair_path: PathBuf,
+	pub commands_path: PathBuf,
+	pub custom_devices_path: PathBuf,
 }
 
 impl FsConfig {
-	pub async fn new(
-		path: PathBuf,
-		cert_file_name: String,
-		keypair_file_name: String,
-		device_folder_name: String,
-	) -> Result<Self> {
-		let device_path = path.join(device_folder_name);
+	pub async fn new(path: PathBuf, names: FsConfigNames) -> Result<Self> {
+		let device_path = path.join(names.devices);
 		create_dir_all(&path).await?;
 		create_dir_all(&device_path).await?;
 		Ok(Self {
-			cert_path: path.join(cert_file_name),
-			keypair_path: path.join(keypair_file_name),
+			cert_path: path.join(names.cert),
+			keypair_path: path.join(names.keypair),
+			commands_path: path.join(names.commands),
+			custom_devices_path: path.join(names.custom_devices),
 			device_path,
 			path,
 		})
 	}
-
-	pub async fn retrieve_all_device_configs(&self) -> Result<Vec<DeviceConfig>> {
-		let mut read_dir = read_dir(&self.device_path).await?;
-		let mut out = Vec::new();
-		while let Ok(Some(entry)) = read_dir.next_entry().await
-			&& entry.metadata().await?.is_file()
-		{
-			out.push(
-				self.retrieve_device_config(
-					entry
-						.file_name()
-						.to_str()
-						.ok_or(KdeConnectError::OsStringConversionError)?,
-				)
-				.await?,
-			);
-		}
-		Ok(out)
-	}
 }
 
 #[async_trait]
@@ -96,4 +115,49 @@ impl ConfigProvider for FsConfig {
 			&tokio::fs::read(self.device_path.join(id)).await?,
 		)?)
 	}
+
+	async fn retrieve_all_device_configs(&self) -> Result<Vec<DeviceConfig>> {
+		let mut read_dir = read_dir(&self.device_path).await?;
+		let mut out = Vec::new();
+		while let Ok(Some(entry)) = read_dir.next_entry().await
+			&& entry.metadata().await?.is_file()
+		{
+			out.push(
+				self.retrieve_device_config(
+					entry
+						.file_name()
+						.to_str()
+						.ok_or(KdeConnectError::OsStringConversionError)?,
+				)
+				.await?,
+			);
+		}
+		Ok(out)
+	}
+
+	async fn store_commands(&self, commands: &HashMap<String, RunCommandItem>) -> Result<()> {
+		Ok(File::create(&self.commands_path)
+			.await?
+			.write_all(&json::to_vec(commands)?)
+			.await?)
+	}
+
+	async fn retrieve_commands(&self) -> Result<HashMap<String, RunCommandItem>> {
+		Ok(json::from_slice(
+			&tokio::fs::read(&self.commands_path).await?,
+		)?)
+	}
+
+	async fn store_custom_devices(&self, devices: &[SocketAddr]) -> Result<()> {
+		Ok(File::create(&self.custom_devices_path)
+			.await?
+			.write_all(&json::to_vec(devices)?)
+			.await?)
+	}
+
+	async fn retrieve_custom_devices(&self) -> Result<Vec<SocketAddr>> {
+		Ok(json::from_slice(
+			&tokio::fs::read(&self.custom_devices_path).await?,
+		)?)
+	}
 }
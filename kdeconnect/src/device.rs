@@ -1,10 +1,10 @@
 use std::{
-	collections::HashMap,
+	collections::{HashMap, HashSet},
 	future::Future,
-	net::IpAddr,
+	io::ErrorKind,
+	net::{IpAddr, SocketAddr},
 	os::unix::fs::MetadataExt,
 	path::Path,
-	pin::Pin,
 	sync::{
 		atomic::{AtomicBool, Ordering},
 		Arc,
@@ -13,17 +13,17 @@ use std::{
 };
 
 use event_listener::Event;
-use log::{debug, error, info, warn};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json as json;
 use sha2::{Digest, Sha256};
 use tokio::{
 	fs::File,
-	io::{split, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf},
+	io::{split, AsyncRead, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
 	net::TcpStream,
 	select,
-	sync::{mpsc, oneshot, Mutex},
-	time::timeout,
+	sync::{mpsc, oneshot, watch, Mutex, Notify},
+	time::{sleep_until, timeout, Instant},
 };
 use tokio_rustls::{
 	rustls::{ClientConfig, ServerConfig},
@@ -32,88 +32,265 @@ use tokio_rustls::{
 
 use crate::{
 	config::ConfigProvider,
-	make_packet, make_packet_payload, make_packet_str, make_packet_str_payload,
+	make_packet, make_packet_payload, make_packet_str,
 	packets::{
-		Battery, BatteryRequest, Clipboard, ClipboardConnect, ConnectivityReport,
-		ConnectivityReportRequest, DeviceType, FindPhone, Identity, MousepadEcho,
+		Battery, Clipboard, ConnectivityReport, ContactsRequestAllUidsTimestamps,
+		ContactsRequestVcardsByUid, DeviceType, FindPhone, Identity, Lock, LockRequest,
 		MousepadKeyboardState, MousepadRequest, Mpris, MprisPlayer, MprisRequest,
-		MprisRequestAction, Packet, PacketPayloadTransferInfo, PacketType, Pair, Ping, Presenter,
-		RunCommand, RunCommandItem, RunCommandRequest, ShareRequest, ShareRequestFile,
-		ShareRequestUpdate, SystemVolume, SystemVolumeRequest, SystemVolumeStream, Telephony,
-		TelephonyRequestMute,
+		MprisRequestAction, Notification, NotificationAction, NotificationReply,
+		NotificationRequest, Packet, PacketPayloadTransferInfo, PacketType, Pair, Ping, Presenter,
+		RunCommand, RunCommandItem, RunCommandRequest, SftpRequest, ShareRequest, ShareRequestFile,
+		ShareRequestUpdate, SmsAddress, SmsMessage, SmsMessages, SmsOutgoingAttachment, SmsRequest,
+		SmsRequestAttachment, SmsRequestConversation, SmsRequestConversations, SystemVolume,
+		SystemVolumeRequest, SystemVolumeStream, Telephony, TelephonyRequestMute,
 	},
-	util::{create_payload, get_payload, get_public_key, get_time_ms},
+	plugins::{
+		sms::{SMS_MESSAGES_VERSION, SMS_REQUEST_VERSION},
+		DevicePlugins, PluginDevice,
+	},
+	send_queue::{QueuedPacket, SendQueue},
+	transfer::{PayloadConfig, TransferHandle},
+	util::{create_payload, get_public_key, get_time_secs, LimitedLines, TlsIdentity},
 	KdeConnectError, Result,
 };
 
 #[derive(Clone)]
-struct LockedDeviceWrite(Arc<Mutex<WriteHalf<TlsStream<BufReader<TcpStream>>>>>);
+pub(crate) struct LockedDeviceWrite {
+	stream: Arc<Mutex<WriteHalf<TlsStream<BufReader<TcpStream>>>>>,
+	write_timeout: Duration,
+	timed_out: Arc<Notify>,
+}
 
 impl LockedDeviceWrite {
-	fn new(stream: WriteHalf<TlsStream<BufReader<TcpStream>>>) -> Self {
-		Self(Arc::new(Mutex::new(stream)))
+	fn new(stream: WriteHalf<TlsStream<BufReader<TcpStream>>>, write_timeout: Duration) -> Self {
+		Self {
+			stream: Arc::new(Mutex::new(stream)),
+			write_timeout,
+			timed_out: Arc::new(Notify::new()),
+		}
+	}
+
+	async fn timed_out(&self) {
+		self.timed_out.notified().await
+	}
+
+	// writes to a dead link can block until the os gives up, which takes minutes
+	pub(crate) async fn send(&self, packet: String) -> std::io::Result<()> {
+		let write = async { self.stream.lock().await.write_all(packet.as_bytes()).await };
+		match timeout(self.write_timeout, write).await {
+			Ok(ret) => ret,
+			Err(_) => {
+				self.timed_out.notify_one();
+				Err(ErrorKind::TimedOut.into())
+			}
+		}
 	}
 
-	async fn send(&self, packet: String) -> std::io::Result<()> {
-		self.0.lock().await.write_all(packet.as_bytes()).await
+	async fn shutdown(&self) -> std::io::Result<()> {
+		let shutdown = async { self.stream.lock().await.shutdown().await };
+		timeout(self.write_timeout, shutdown)
+			.await
+			.unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()))
 	}
 }
 
+// only for the rarely used requests of DeviceClient, packets go through SendQueue
+const DEVICE_ACTION_QUEUE_SIZE: usize = 16;
+
+// limits of device links, built once by KdeConnect when the server starts
+#[derive(Clone, Debug)]
+pub(crate) struct LinkSettings {
+	pub(crate) pairing_timeout: Duration,
+	pub(crate) pair_timestamp_tolerance: Duration,
+	pub(crate) write_timeout: Duration,
+	// pings are sent after this long without packets from the device
+	pub(crate) ping_interval: Option<Duration>,
+	pub(crate) ping_timeout: Duration,
+	pub(crate) max_packet_size: usize,
+	pub(crate) send_queue_size: usize,
+	pub(crate) payload: PayloadConfig,
+}
+
+// state of KdeConnect every device link takes part in
+#[derive(Clone)]
+pub(crate) struct LinkShared {
+	pub(crate) config_provider: Arc<dyn ConfigProvider + Sync + Send>,
+	pub(crate) tls_identity: TlsIdentity,
+	pub(crate) connected_clients: Arc<Mutex<Vec<String>>>,
+	pub(crate) pair_timestamps: Arc<Mutex<HashMap<String, u64>>>,
+	pub(crate) disconnected: mpsc::UnboundedSender<DeviceConfig>,
+	// set when the server shuts down
+	pub(crate) shutdown: watch::Receiver<bool>,
+}
+
+// basically whenever tcp connection is established identity packet gets sent
+// then tls starts, only if device is trusted does cert get verified against the stored one
+// once in tls untrusted devices can be trusted by sending pair and then storing
+// device's cert to verify
 pub(crate) async fn create_device(
 	identity: Identity,
-	config_provider: Arc<dyn ConfigProvider + Sync + Send>,
+	protocol_version: usize,
 	stream: TlsStream<BufReader<TcpStream>>,
-	connected_clients: Arc<Mutex<Vec<String>>>,
-	server_config: Arc<ServerConfig>,
-	client_config: Arc<ClientConfig>,
+	capabilities: DeviceCapabilities,
+	settings: LinkSettings,
+	shared: LinkShared,
 ) -> Result<(Device, DeviceClient)> {
-	let device_config = config_provider
+	let LinkShared {
+		config_provider,
+		tls_identity,
+		connected_clients,
+		pair_timestamps,
+		disconnected,
+		shutdown,
+	} = shared;
+
+	let (cert, last_address) = config_provider
 		.retrieve_device_config(&identity.device_id)
 		.await
-		.ok();
+		.map_or((None, None), |x| (x.certificate, x.last_address));
+
+	let stream_cert = stream
+		.get_ref()
+		.1
+		.peer_certificates()
+		.ok_or(KdeConnectError::NoPeerCerts)?[0]
+		.to_vec();
+
+	// payload transfers must come from the same device as the link
+	let server_config = tls_identity.server_config(Some(stream_cert.clone()))?;
+	let client_config = tls_identity.client_config(Some(stream_cert.clone()))?;
+
+	let addr = stream.get_ref().0.get_ref().peer_addr()?;
+	let local_ip = stream.get_ref().0.get_ref().local_addr()?.ip();
+
+	let config = DeviceConfig {
+		id: identity.device_id,
+		name: identity.device_name,
+		device_type: identity.device_type,
+		certificate: cert,
+		last_address: Some(addr.ip()),
+	};
+	// remember where paired devices are so they can be reconnected to
+	if config.is_paired() && last_address != Some(addr.ip()) {
+		config_provider.store_device_config(&config).await?;
+	}
 
-	let (client_tx, client_rx) = mpsc::unbounded_channel();
+	let (client_tx, client_rx) = mpsc::channel(DEVICE_ACTION_QUEUE_SIZE);
+	let send_queue = Arc::new(SendQueue::new(settings.send_queue_size));
 
 	let initiated_pair = Arc::new(AtomicBool::new(false));
 	let pair_event = Arc::new(Event::new());
-
-	Ok((
-		Device::new(
-			identity,
-			device_config,
-			config_provider,
-			stream,
-			connected_clients,
-			client_rx,
-			initiated_pair.clone(),
-			pair_event.clone(),
-			client_config,
-			server_config.clone(),
-		)
-		.await?,
-		DeviceClient::new(client_tx, initiated_pair, pair_event, server_config),
-	))
+	let capabilities = Arc::new(capabilities);
+
+	let (r, w) = split(stream);
+
+	let client = DeviceClient {
+		client_w: client_tx,
+		send_queue: send_queue.clone(),
+
+		initiated_pair: initiated_pair.clone(),
+		pair_event: pair_event.clone(),
+		local_ip,
+		server_config: server_config.clone(),
+		settings: settings.clone(),
+
+		capabilities: capabilities.clone(),
+	};
+	let device = Device {
+		config,
+		protocol_version,
+		capabilities,
+
+		config_provider,
+		connected_clients,
+		disconnected,
+		shutdown,
+
+		server_config,
+		client_config,
+
+		stream_r: LimitedLines::new(BufReader::new(r), settings.max_packet_size),
+		stream_w: LockedDeviceWrite::new(w, settings.write_timeout),
+		stream_cert,
+
+		client_r: client_rx,
+		send_queue,
+		addr,
+		local_ip,
+
+		initiated_pair,
+		pair_event,
+		pair_timestamps,
+		settings,
+	};
+	Ok((device, client))
 }
 
 pub struct Device {
 	pub config: DeviceConfig,
+	pub protocol_version: usize,
+	capabilities: Arc<DeviceCapabilities>,
 	config_provider: Arc<dyn ConfigProvider + Sync + Send>,
 	connected_clients: Arc<Mutex<Vec<String>>>,
+	disconnected: mpsc::UnboundedSender<DeviceConfig>,
+	// set when the server shuts down
+	shutdown: watch::Receiver<bool>,
 
 	server_config: Arc<ServerConfig>,
 	client_config: Arc<ClientConfig>,
 
-	stream_r: Lines<BufReader<ReadHalf<TlsStream<BufReader<TcpStream>>>>>,
+	stream_r: LimitedLines<BufReader<ReadHalf<TlsStream<BufReader<TcpStream>>>>>,
 	stream_w: LockedDeviceWrite,
 	stream_cert: Vec<u8>,
 
-	client_r: mpsc::UnboundedReceiver<DeviceAction>,
-	ip: IpAddr,
+	client_r: mpsc::Receiver<DeviceAction>,
+	send_queue: Arc<SendQueue>,
+	addr: SocketAddr,
+	local_ip: IpAddr,
 
 	initiated_pair: Arc<AtomicBool>,
 	pair_event: Arc<Event>,
+	pair_timestamps: Arc<Mutex<HashMap<String, u64>>>,
+	settings: LinkSettings,
+}
+
+// packet types both sides declared support for
+#[derive(Clone, Debug)]
+pub struct DeviceCapabilities {
+	// packets we accept from the device
+	pub incoming: HashSet<String>,
+	// packets we can send to the device
+	pub outgoing: HashSet<String>,
+}
 
-	mpris_supports_album_art: bool,
+impl DeviceCapabilities {
+	pub(crate) fn new(
+		identity: &Identity,
+		own_incoming: &[String],
+		own_outgoing: &[String],
+	) -> Self {
+		Self {
+			incoming: own_incoming
+				.iter()
+				.filter(|x| identity.outgoing_capabilities.contains(x))
+				.cloned()
+				.collect(),
+			outgoing: own_outgoing
+				.iter()
+				.filter(|x| identity.incoming_capabilities.contains(x))
+				.cloned()
+				.collect(),
+		}
+	}
+
+	// pairing is part of the protocol and never declared
+	pub fn can_receive(&self, packet_type: &str) -> bool {
+		packet_type == Pair::TYPE || self.incoming.contains(packet_type)
+	}
+
+	pub fn can_send(&self, packet_type: &str) -> bool {
+		packet_type == Pair::TYPE || self.outgoing.contains(packet_type)
+	}
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -122,6 +299,9 @@ pub struct DeviceConfig {
 	pub name: String,
 	pub device_type: DeviceType,
 	pub certificate: Option<Vec<u8>>,
+	// where the device was last connected from, used to reconnect
+	#[serde(default)]
+	pub last_address: Option<IpAddr>,
 }
 
 impl DeviceConfig {
@@ -131,7 +311,6 @@ impl DeviceConfig {
 }
 
 pub(crate) enum DeviceAction {
-	SendPacket(String, oneshot::Sender<Result<()>>),
 	GetConfig(oneshot::Sender<DeviceConfig>),
 	GetKey(oneshot::Sender<Result<String>>),
 	GetPaired(oneshot::Sender<bool>),
@@ -141,78 +320,38 @@ pub(crate) enum DeviceAction {
 enum DeviceEvent {
 	Stream(String),
 	Client(DeviceAction),
+	Send(QueuedPacket),
+	Probe,
+	PluginTimeout,
 }
 
 impl Device {
-	// basically whenever tcp connection is established identity packet gets sent
-	// then tls starts, only if device is trusted does cert get verified
-	// once in tls untrusted devices can be trusted by sending pair and then storing
-	// device's cert to verify
-	#[allow(clippy::too_many_arguments)]
-	pub(crate) async fn new(
-		identity: Identity,
-		conf: Option<DeviceConfig>,
-		config_provider: Arc<dyn ConfigProvider + Sync + Send>,
-		stream: TlsStream<BufReader<TcpStream>>,
-		connected_clients: Arc<Mutex<Vec<String>>>,
-		client_r: mpsc::UnboundedReceiver<DeviceAction>,
-		initiated_pair: Arc<AtomicBool>,
-		pair_event: Arc<Event>,
-		client_config: Arc<ClientConfig>,
-		server_config: Arc<ServerConfig>,
-	) -> Result<Self> {
-		let cert = conf.and_then(|x| x.certificate);
-
-		let stream_cert = stream
-			.get_ref()
-			.1
-			.peer_certificates()
-			.ok_or(KdeConnectError::NoPeerCerts)?[0]
-			.to_vec();
-
-		let ip = stream.get_ref().0.get_ref().peer_addr()?.ip();
-
-		let (r, w) = split(stream);
-
-		Ok(Self {
-			config: DeviceConfig {
-				id: identity.device_id,
-				name: identity.device_name,
-				device_type: identity.device_type,
-				certificate: cert,
-			},
-
-			config_provider,
-			connected_clients,
-
-			server_config,
-			client_config,
-
-			stream_r: BufReader::new(r).lines(),
-			stream_w: LockedDeviceWrite::new(w),
-			stream_cert,
-
-			client_r,
-			ip,
-
-			initiated_pair,
-			pair_event,
-
-			mpris_supports_album_art: false,
-		})
-	}
-
-	pub async fn task(&mut self, mut handler: Box<dyn DeviceHandler + Sync + Send>) -> Result<()> {
-		self.send_paired_data(&mut handler).await?;
-		let ret = self.inner_task(&mut handler).await;
+	pub async fn task(
+		&mut self,
+		mut handler: Box<dyn DeviceHandler + Sync + Send>,
+		mut plugins: DevicePlugins,
+	) -> Result<()> {
+		let ret = self.inner_task(&mut handler, &mut plugins).await;
+		self.send_queue.close().await;
+		if let Err(err) = self.stream_w.shutdown().await {
+			debug!("failed to close link to {}: {:?}", self.config.id, err);
+		}
+		plugins.handle_exit().await;
 		handler.handle_exit().await;
 		self.connected_clients
 			.lock()
 			.await
 			.retain(|x| *x != self.config.id);
+		if self.is_paired() {
+			let _ = self.disconnected.send(self.config.clone());
+		}
 		ret
 	}
 
+	pub fn capabilities(&self) -> &DeviceCapabilities {
+		&self.capabilities
+	}
+
 	pub async fn get_verification_key(&self) -> Result<String> {
 		let mut own_key = get_public_key(&self.config_provider.retrieve_server_cert().await?)?;
 		let mut device_key = get_public_key(&self.stream_cert)?;
@@ -226,27 +365,22 @@ impl Device {
 		Ok(hex::encode(digest))
 	}
 
-	async fn send_paired_data(
-		&self,
-		handler: &mut Box<dyn DeviceHandler + Sync + Send>,
-	) -> Result<()> {
-		if self.config.certificate.is_some() {
-			let battery = handler.get_battery().await;
-			self.stream_w.send(make_packet_str!(battery)?).await?;
-
-			let clipboard = ClipboardConnect {
-				content: handler.get_clipboard_content().await,
-				timestamp: get_time_ms(),
-			};
-			self.stream_w.send(make_packet_str!(clipboard)?).await?;
-
-			let connectivity = handler.get_connectivity_report().await;
-			self.stream_w.send(make_packet_str!(connectivity)?).await?;
+	fn plugin_device(&self) -> PluginDevice<'_> {
+		PluginDevice {
+			config: &self.config,
+			capabilities: &self.capabilities,
+			stream_w: &self.stream_w,
+			addr: self.addr,
+			local_ip: self.local_ip,
+			client_config: &self.client_config,
+			server_config: &self.server_config,
+			payload_config: &self.settings.payload,
+		}
+	}
 
-			let system_volume = SystemVolume::List {
-				sink_list: handler.get_system_volume().await,
-			};
-			self.stream_w.send(make_packet_str!(system_volume)?).await?;
+	async fn send_paired_data(&self, plugins: &mut DevicePlugins) -> Result<()> {
+		if self.config.certificate.is_some() {
+			plugins.send_state(&self.plugin_device()).await?;
 		}
 		Ok(())
 	}
@@ -255,26 +389,89 @@ impl Device {
 		self.config.is_paired()
 	}
 
+	// protocol v8 pair requests must carry a recent timestamp that hasn't been seen before
+	async fn is_valid_pair_timestamp(&self, timestamp: Option<u64>) -> bool {
+		if self.protocol_version < 8 {
+			return true;
+		}
+		let Some(timestamp) = timestamp else {
+			return false;
+		};
+		let now = get_time_secs();
+		now.abs_diff(timestamp) <= self.settings.pair_timestamp_tolerance.as_secs()
+			&& self
+				.pair_timestamps
+				.lock()
+				.await
+				.get(&self.config.id)
+				.map_or(true, |x| timestamp > *x)
+	}
+
+	async fn remember_pair_timestamp(&self, timestamp: u64) {
+		let now = get_time_secs();
+		let tolerance = self.settings.pair_timestamp_tolerance.as_secs();
+		let mut pair_timestamps = self.pair_timestamps.lock().await;
+		// anything older than the tolerance is refused anyway
+		pair_timestamps.retain(|_, x| now.abs_diff(*x) <= tolerance);
+		pair_timestamps.insert(self.config.id.clone(), timestamp);
+	}
+
 	async fn inner_task(
 		&mut self,
 		handler: &mut Box<dyn DeviceHandler + Sync + Send>,
+		plugins: &mut DevicePlugins,
 	) -> Result<()> {
-		while let Some(evt) = select! {
-			x = self.stream_r.next_line() => x?.map(DeviceEvent::Stream),
-			x = self.client_r.recv() => x.map(DeviceEvent::Client),
+		self.send_paired_data(plugins).await?;
+		let mut last_activity = Instant::now();
+		// set while a ping waits for anything to arrive from the device
+		let mut probe_deadline: Option<Instant> = None;
+		while let Some(evt) = {
+			let probe_at = last_activity + self.settings.ping_interval.unwrap_or_default();
+			let deadline = probe_deadline.unwrap_or(probe_at);
+			let probe = self.settings.ping_interval.is_some() && probe_deadline.is_none();
+			let plugin_at = plugins.next_timeout();
+			select! {
+				x = self.stream_r.next_line() => x?.map(DeviceEvent::Stream),
+				x = self.client_r.recv() => x.map(DeviceEvent::Client),
+				x = self.send_queue.pop() => Some(DeviceEvent::Send(x)),
+				_ = self.shutdown.wait_for(|x| *x) => None,
+				_ = self.stream_w.timed_out() => return Err(KdeConnectError::LinkTimedOut),
+				_ = sleep_until(deadline), if probe_deadline.is_some() => {
+					return Err(KdeConnectError::LinkTimedOut)
+				}
+				_ = sleep_until(probe_at), if probe => {
+					Some(DeviceEvent::Probe)
+				}
+				_ = sleep_until(plugin_at.unwrap_or(probe_at)), if plugin_at.is_some() => {
+					Some(DeviceEvent::PluginTimeout)
+				}
+			}
 		} {
 			match evt {
+				DeviceEvent::PluginTimeout => plugins.handle_timeouts().await,
+				DeviceEvent::Probe => {
+					// writes to a dead link usually succeed, so the device has to send something
+					// back before ping_timeout
+					if self.capabilities.can_send(Ping::TYPE) {
+						let ping = Ping { message: None };
+						self.stream_w.send(make_packet_str!(ping)?).await?;
+						probe_deadline = Some(Instant::now() + self.settings.ping_timeout);
+					}
+					last_activity = Instant::now();
+				}
 				DeviceEvent::Stream(buf) => {
+					last_activity = Instant::now();
+					probe_deadline = None;
 					let packet: Packet = json::from_str(&buf)?;
+					if !self.capabilities.can_receive(&packet.packet_type) {
+						debug!(
+							"{} sent undeclared packet type {:?}, dropping",
+							self.config.id, packet.packet_type
+						);
+						continue;
+					}
 
 					match packet.packet_type.as_str() {
-						Ping::TYPE => {
-							let body: Ping = json::from_value(packet.body)?;
-							debug!("recieved ping: {:?}", body);
-							handler.handle_ping(body.clone()).await;
-							self.stream_w.send(make_packet_str!(body)?).await?;
-							self.send_paired_data(handler).await?;
-						}
 						Pair::TYPE => {
 							let body: Pair = json::from_value(packet.body)?;
 							let initiated_pair = self.initiated_pair.load(Ordering::Acquire);
@@ -289,12 +486,29 @@ impl Device {
 									"{} asking to unpair when already unpaired??",
 									self.config.id
 								);
+							} else if !self.is_paired()
+								&& body.pair && !initiated_pair
+								&& !self.is_valid_pair_timestamp(body.timestamp).await
+							{
+								warn!(
+									"{} sent pair request with invalid timestamp {:?}, refusing",
+									self.config.id, body.timestamp
+								);
+								let pair_packet = Pair {
+									pair: false,
+									timestamp: None,
+								};
+								self.stream_w.send(make_packet_str!(pair_packet)?).await?;
 							} else if !self.is_paired() && body.pair {
 								// pairing
 
+								if let Some(timestamp) = body.timestamp {
+									self.remember_pair_timestamp(timestamp).await;
+								}
+
 								let should_pair = initiated_pair
 									|| timeout(
-										Duration::from_secs(30),
+										self.settings.pairing_timeout,
 										handler.handle_pairing_request(),
 									)
 									.await
@@ -304,7 +518,10 @@ impl Device {
 
 								if !initiated_pair {
 									// send response if other side requested pair
-									let pair_packet = Pair { pair: should_pair };
+									let pair_packet = Pair {
+										pair: should_pair,
+										timestamp: None,
+									};
 									self.stream_w.send(make_packet_str!(pair_packet)?).await?;
 								}
 
@@ -316,7 +533,8 @@ impl Device {
 										.store_device_config(&self.config)
 										.await?;
 									handler.handle_pair_status_change(true).await;
-									self.send_paired_data(handler).await?;
+									plugins.handle_pair_status_change(true).await;
+									self.send_paired_data(plugins).await?;
 								}
 
 								if initiated_pair {
@@ -337,256 +555,47 @@ impl Device {
 									.store_device_config(&self.config)
 									.await?;
 								handler.handle_pair_status_change(false).await;
+								plugins.handle_pair_status_change(false).await;
 							}
 						}
-						Battery::TYPE => {
-							handler.handle_battery(json::from_value(packet.body)?).await;
-						}
-						BatteryRequest::TYPE => {
-							let battery = handler.get_battery().await;
-							self.stream_w.send(make_packet_str!(battery)?).await?;
-						}
-						Clipboard::TYPE => {
-							let clipboard: Clipboard = json::from_value(packet.body)?;
-							handler.handle_clipboard_content(clipboard.content).await;
-						}
-						ClipboardConnect::TYPE => {
-							let connect: ClipboardConnect = json::from_value(packet.body)?;
-							if connect.timestamp != 0 {
-								handler.handle_clipboard_content(connect.content).await;
-							}
-						}
-						FindPhone::TYPE => {
-							handler.handle_find_phone().await;
-						}
-						ConnectivityReport::TYPE => {
-							handler
-								.handle_connectivity_report(json::from_value(packet.body)?)
-								.await;
-						}
-						ConnectivityReportRequest::TYPE => {
-							let connectivity = handler.get_connectivity_report().await;
-							self.stream_w.send(make_packet_str!(connectivity)?).await?;
-						}
-						Presenter::TYPE => {
-							handler
-								.handle_presenter(json::from_value(packet.body)?)
-								.await;
-						}
-						SystemVolume::TYPE => {
-							handler
-								.handle_system_volume(json::from_value(packet.body)?)
-								.await;
-						}
-						SystemVolumeRequest::TYPE => {
-							let request: SystemVolumeRequest = json::from_value(packet.body)?;
-							if request.request_sinks.unwrap_or(false) {
-								let system_volume = SystemVolume::List {
-									sink_list: handler.get_system_volume().await,
-								};
-								self.stream_w.send(make_packet_str!(system_volume)?).await?;
-							} else {
-								handler.handle_system_volume_request(request).await;
-							}
-						}
-						ShareRequestUpdate::TYPE => {
-							let update: ShareRequestUpdate = json::from_value(packet.body)?;
-							handler.handle_multi_file_share(update).await;
-						}
-						ShareRequest::TYPE => {
-							// weird bug, fails to deser ShareRequestFile variant so we do it
-							// manually
-							let request: ShareRequest =
-								if let Ok(request_file) = json::from_value(packet.body.clone()) {
-									ShareRequest::File(request_file)
-								} else {
-									json::from_value(packet.body)?
-								};
-							if let Some(transfer_info) = packet.payload_transfer_info
-								&& let Some(size) = packet.payload_size
-								&& let ShareRequest::File(file) = request
-							{
-								handler
-									.handle_file_share(
-										file,
-										size,
-										get_payload(
-											self.ip,
-											transfer_info,
-											self.client_config.clone(),
-										)
-										.await?,
-									)
-									.await;
-							} else {
-								match request {
-									ShareRequest::Text { text } => {
-										handler.handle_text_share(text).await;
-									}
-									ShareRequest::Url { url } => {
-										handler.handle_url_share(url).await;
-									}
-									ShareRequest::File(_) => {} // ignore - no payload transfer info
-								}
-							}
-						}
-						Mpris::TYPE => {
-							let mpris: Mpris = json::from_value(packet.body)?;
-							match mpris {
-								Mpris::List {
-									player_list,
-									supports_album_art_payload,
-								} => {
-									self.mpris_supports_album_art = supports_album_art_payload;
-									handler.handle_mpris_player_list(player_list).await;
-								}
-								Mpris::TransferringArt {
-									player,
-									album_art_url: _,
-									transferring_album_art,
-								} => {
-									if transferring_album_art
-										&& let Some(transfer_info) = packet.payload_transfer_info
-									{
-										handler
-											.handle_mpris_player_album_art(
-												player,
-												get_payload(
-													self.ip,
-													transfer_info,
-													self.client_config.clone(),
-												)
-												.await?,
-											)
-											.await;
-									}
-								}
-								Mpris::Info(player) => {
-									handler.handle_mpris_player_info(player).await;
-								}
-							}
+						_ if !self.is_paired() => {
+							// only pairing is allowed before the device is trusted
+							warn!(
+								"{} sent {:?} while not paired, dropping",
+								self.config.id, packet.packet_type
+							);
 						}
-						MprisRequest::TYPE => {
-							let req: MprisRequest = json::from_value(packet.body)?;
-							match req {
-								MprisRequest::List { .. } => {
-									let packet = Mpris::List {
-										player_list: handler.get_mpris_player_list().await,
-										supports_album_art_payload: true,
-									};
-									self.stream_w.send(make_packet_str!(packet)?).await?;
-								}
-								MprisRequest::PlayerRequest {
-									player,
-									request_album_art,
-									..
-								} => {
-									if let Some(player_info) =
-										handler.get_mpris_player(player.clone()).await
-									{
-										if let Some(url) = request_album_art
-											&& url.starts_with("file://") && player_info
-											.album_art_url
-											.as_ref()
-											.map(|x| *x == url)
-											.unwrap_or(false)
-										{
-											let server_conf = self.server_config.clone();
-											let ret = async {
-												let art =
-													File::open(url.trim_start_matches("file://"))
-														.await?;
-												let size = art.metadata().await?.size();
-												let (port, fut) =
-													create_payload(art, server_conf).await?;
-												let packet = Mpris::TransferringArt {
-													player,
-													album_art_url: url,
-													transferring_album_art: true,
-												};
-												self.stream_w
-													.send(make_packet_str_payload!(
-														packet,
-														size as i64,
-														port
-													)?)
-													.await?;
-												tokio::spawn(fut);
-												Ok::<(), KdeConnectError>(())
-											}
-											.await;
-											if let Err(e) = ret {
-												error!("failed to send album art: {:?}", e);
-											}
-										}
-										let packet = Mpris::Info(player_info);
-										self.stream_w.send(make_packet_str!(packet)?).await?;
-									}
-								}
-								MprisRequest::Action(action) => {
-									handler.handle_mpris_player_action(action).await;
-								}
+						_ => {
+							let is_ping = packet.packet_type == Ping::TYPE;
+							let device = self.plugin_device();
+							if let Some(packet) = plugins.handle_packet(&device, packet).await? {
+								debug!("no plugin handles {:?}", packet.packet_type);
+								handler.handle_unknown_packet(packet).await;
 							}
-						}
-						MousepadRequest::TYPE => {
-							handler
-								.handle_mousepad_request(json::from_value(packet.body)?)
-								.await;
-						}
-						MousepadEcho::TYPE => {
-							handler
-								.handle_mousepad_echo(json::from_value(packet.body)?)
-								.await;
-						}
-						MousepadKeyboardState::TYPE => {
-							handler
-								.handle_mousepad_keyboard_state(json::from_value(packet.body)?)
-								.await;
-						}
-						RunCommand::TYPE => {
-							let packet: RunCommand = json::from_value(packet.body)?;
-							let list: HashMap<String, RunCommandItem> =
-								json::from_str(&packet.command_list)?;
-							handler.handle_command_list(list).await;
-						}
-						RunCommandRequest::TYPE => {
-							let packet: RunCommandRequest = json::from_value(packet.body)?;
-							if packet.request_command_list.unwrap_or(false) {
-								let command_list = handler.get_command_list().await;
-								let packet = RunCommand {
-									command_list: json::to_string(&command_list)?,
-								};
-								self.stream_w.send(make_packet_str!(packet)?).await?;
-							} else if let Some(command_id) = packet.key {
-								handler.handle_command_request(command_id).await;
+							// pings are also used to refresh the state of the other side
+							if is_ping {
+								self.send_paired_data(plugins).await?;
 							}
 						}
-						Telephony::TYPE => {
-							handler
-								.handle_telephony(json::from_value(packet.body)?)
-								.await;
-						}
-						TelephonyRequestMute::TYPE => {
-							handler.handle_telephony_mute_request().await;
-						}
-						_ => error!(
-							"unknown type {:?}, ignoring: {:#?}",
-							packet.packet_type, packet.body
-						),
+					}
+				}
+				DeviceEvent::Send(queued) => {
+					info!("packet {:?}", queued.packet);
+					let ret = self
+						.stream_w
+						.send(json::to_string(&queued.packet)? + "\n")
+						.await;
+					for response in queued.responders {
+						let _ = response.send(
+							ret.as_ref()
+								.map(|_| ())
+								.map_err(|x| std::io::Error::new(x.kind(), x.to_string()).into()),
+						);
 					}
 				}
 				DeviceEvent::Client(action) => {
 					use DeviceAction as A;
 					match action {
-						A::SendPacket(packet, response) => {
-							info!("packet {:?}", packet);
-							let _ = response.send(
-								self.stream_w
-									.send(packet)
-									.await
-									.map_err(KdeConnectError::from),
-							);
-						}
 						A::GetConfig(response) => {
 							let _ = response.send(self.config.clone());
 						}
@@ -599,10 +608,14 @@ impl Device {
 						A::Unpair => {
 							self.config.certificate.take();
 							handler.handle_pair_status_change(false).await;
+							plugins.handle_pair_status_change(false).await;
 							self.config_provider
 								.store_device_config(&self.config)
 								.await?;
-							let pair_packet = Pair { pair: false };
+							let pair_packet = Pair {
+								pair: false,
+								timestamp: None,
+							};
 							self.stream_w.send(make_packet_str!(pair_packet)?).await?;
 						}
 					}
@@ -614,60 +627,93 @@ impl Device {
 }
 
 pub struct DeviceClient {
-	client_w: mpsc::UnboundedSender<DeviceAction>,
+	client_w: mpsc::Sender<DeviceAction>,
+	send_queue: Arc<SendQueue>,
 	initiated_pair: Arc<AtomicBool>,
+	local_ip: IpAddr,
 	server_config: Arc<ServerConfig>,
+	settings: LinkSettings,
 
 	pair_event: Arc<Event>,
+
+	capabilities: Arc<DeviceCapabilities>,
 }
 
 impl DeviceClient {
-	pub(crate) fn new(
-		client_w: mpsc::UnboundedSender<DeviceAction>,
-		initiated_pair: Arc<AtomicBool>,
-		pair_event: Arc<Event>,
-		server_config: Arc<ServerConfig>,
-	) -> Self {
-		Self {
-			client_w,
+	pub fn capabilities(&self) -> &DeviceCapabilities {
+		&self.capabilities
+	}
+
+	/// Sends any packet, the device must have declared support for its type.
+	pub async fn send_raw(&self, packet: Packet) -> Result<()> {
+		self.send_packet(packet).await
+	}
 
-			initiated_pair,
-			pair_event,
-			server_config,
+	/// Sends any packet with a payload. Returns once the payload has been transferred.
+	pub async fn send_raw_with_payload(
+		&self,
+		mut packet: Packet,
+		payload: DevicePayload<impl AsyncRead + Sync + Send + Unpin>,
+	) -> Result<()> {
+		if !self.capabilities.can_send(&packet.packet_type) {
+			return Err(KdeConnectError::UnsupportedPacketType(packet.packet_type));
 		}
+		let (port, fut) = self
+			.create_payload(payload.buf, TransferHandle::new(payload.size))
+			.await?;
+		packet.payload_size = Some(payload.size);
+		packet.payload_transfer_info = Some(PacketPayloadTransferInfo { port });
+		self.send_packet(packet).await?;
+		fut.await
 	}
 
-	async fn send_packet(&self, packet: String) -> Result<()> {
-		let (tx, rx) = oneshot::channel();
-		self.client_w.send(DeviceAction::SendPacket(packet, tx))?;
-		rx.await?
+	async fn create_payload(
+		&self,
+		payload: impl AsyncRead + Sync + Send + Unpin,
+		transfer: TransferHandle,
+	) -> Result<(u16, impl Future<Output = Result<()>> + Sync + Send)> {
+		create_payload(
+			payload,
+			self.local_ip,
+			self.server_config.clone(),
+			self.settings.payload.clone(),
+			transfer,
+		)
+		.await
+	}
+
+	async fn send_packet(&self, packet: Packet) -> Result<()> {
+		if !self.capabilities.can_send(&packet.packet_type) {
+			return Err(KdeConnectError::UnsupportedPacketType(packet.packet_type));
+		}
+		self.send_queue.push(packet).await.await?
 	}
 
 	pub async fn send_ping(&self, message: Option<String>) -> Result<()> {
 		let ping = Ping { message };
-		self.send_packet(make_packet_str!(ping)?).await
+		self.send_packet(make_packet!(ping)).await
 	}
 
 	pub async fn send_battery_update(&self, packet: Battery) -> Result<()> {
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn send_clipboard_update(&self, content: String) -> Result<()> {
 		let packet = Clipboard { content };
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn send_connectivity_report(&self, packet: ConnectivityReport) -> Result<()> {
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn send_presenter_update(&self, packet: Presenter) -> Result<()> {
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn send_volume_update(&self, streams: Vec<SystemVolumeStream>) -> Result<()> {
 		let packet = SystemVolume::List { sink_list: streams };
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn send_volume_stream_update(
@@ -683,7 +729,7 @@ impl DeviceClient {
 			muted,
 			volume,
 		};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn request_volume_list(&self) -> Result<()> {
@@ -694,7 +740,7 @@ impl DeviceClient {
 			muted: None,
 			volume: None,
 		};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn send_volume_request(
@@ -711,18 +757,18 @@ impl DeviceClient {
 			muted,
 			volume,
 		};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn get_config(&self) -> Result<DeviceConfig> {
 		let (tx, rx) = oneshot::channel();
-		self.client_w.send(DeviceAction::GetConfig(tx))?;
+		self.client_w.send(DeviceAction::GetConfig(tx)).await?;
 		Ok(rx.await?)
 	}
 
 	pub async fn is_paired(&self) -> Result<bool> {
 		let (tx, rx) = oneshot::channel();
-		self.client_w.send(DeviceAction::GetPaired(tx))?;
+		self.client_w.send(DeviceAction::GetPaired(tx)).await?;
 		Ok(rx.await?)
 	}
 
@@ -731,12 +777,23 @@ impl DeviceClient {
 		if new_state && self.is_paired().await? {
 			return Err(KdeConnectError::DeviceAlreadyPaired);
 		}
-		let pair = Pair { pair: new_state };
-		self.send_packet(make_packet_str!(pair)?).await?;
 		// trying to pair? if so wait for pair response
 		if new_state {
+			let pair = Pair {
+				pair: true,
+				timestamp: Some(get_time_secs()),
+			};
+			// listen before sending so a quick response isn't missed
+			let listener = self.pair_event.listen();
 			self.initiated_pair.store(true, Ordering::Release);
-			self.pair_event.listen().await;
+			self.send_packet(make_packet!(pair)).await?;
+			if timeout(self.settings.pairing_timeout, listener)
+				.await
+				.is_err()
+			{
+				self.initiated_pair.store(false, Ordering::Release);
+				return Err(KdeConnectError::DevicePairTimedOut);
+			}
 			self.is_paired().await.and_then(|x| {
 				if x {
 					Ok(())
@@ -745,30 +802,35 @@ impl DeviceClient {
 				}
 			})
 		} else {
-			self.client_w.send(DeviceAction::Unpair)?;
+			let pair = Pair {
+				pair: false,
+				timestamp: None,
+			};
+			self.send_packet(make_packet!(pair)).await?;
+			self.client_w.send(DeviceAction::Unpair).await?;
 			Ok(())
 		}
 	}
 
 	pub async fn toggle_find_phone(&self) -> Result<()> {
 		let packet = FindPhone {};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn get_verification_key(&self) -> Result<String> {
 		let (tx, rx) = oneshot::channel();
-		self.client_w.send(DeviceAction::GetKey(tx))?;
+		self.client_w.send(DeviceAction::GetKey(tx)).await?;
 		rx.await?
 	}
 
 	pub async fn share_text(&self, text: String) -> Result<()> {
 		let packet = ShareRequest::Text { text };
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn share_url(&self, url: String) -> Result<()> {
 		let packet = ShareRequest::Url { url };
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	async fn share_file_internal(
@@ -777,8 +839,9 @@ impl DeviceClient {
 		open: bool,
 		number_of_files: Option<i32>,
 		total_payload_size: Option<i64>,
+		transfer: TransferHandle,
 	) -> Result<()> {
-		let (port, fut) = create_payload(file.buf, self.server_config.clone()).await?;
+		let (port, fut) = self.create_payload(file.buf, transfer).await?;
 		let packet = ShareRequest::File(ShareRequestFile {
 			filename: file.name,
 			creation_time: file.creation_time,
@@ -787,36 +850,58 @@ impl DeviceClient {
 			number_of_files,
 			total_payload_size,
 		});
-		self.send_packet(make_packet_str_payload!(packet, file.size, port)?)
+		self.send_packet(make_packet_payload!(packet, file.size, port))
 			.await?;
-		fut.await;
-		Ok(())
+		fut.await
 	}
 
+	/// Returns once the file has been transferred.
 	pub async fn share_file(
 		&self,
 		file: DeviceFile<impl AsyncRead + Sync + Send + Unpin>,
 		open: bool,
 	) -> Result<()> {
-		self.share_file_internal(file, open, None, None).await
+		let transfer = TransferHandle::new(file.size);
+		self.share_file_tracked(file, open, transfer).await
+	}
+
+	/// Like share_file, transfer can be used to follow the progress or cancel it.
+	pub async fn share_file_tracked(
+		&self,
+		file: DeviceFile<impl AsyncRead + Sync + Send + Unpin>,
+		open: bool,
+		transfer: TransferHandle,
+	) -> Result<()> {
+		self.share_file_internal(file, open, None, None, transfer)
+			.await
 	}
 
+	/// Returns one future per file that sends it, transfer gets the progress of all files together
+	/// and cancels every file.
 	pub async fn share_files_manual<'a>(
 		&'a self,
 		files: Vec<DeviceFile<impl AsyncRead + Sync + Send + Unpin + 'a>>,
 		open: bool,
+		transfer: &TransferHandle,
 	) -> Result<Vec<impl Future<Output = Result<()>> + Sync + Send + 'a>> {
 		let mut total_size: i64 = files.iter().map(|x| x.size).sum();
 		let mut file_cnt = files.len() as i32;
+		transfer.set_total(u64::try_from(total_size).ok());
 		let multi_packet = ShareRequestUpdate {
 			number_of_files: Some(file_cnt),
 			total_payload_size: Some(total_size),
 		};
-		self.send_packet(make_packet_str!(multi_packet)?).await?;
+		self.send_packet(make_packet!(multi_packet)).await?;
 		let mut futs = Vec::with_capacity(files.len());
 		for file in files {
 			let file_size = file.size;
-			futs.push(self.share_file_internal(file, open, Some(file_cnt), Some(total_size)));
+			futs.push(self.share_file_internal(
+				file,
+				open,
+				Some(file_cnt),
+				Some(total_size),
+				transfer.child(file_size),
+			));
 			file_cnt -= 1;
 			total_size -= file_size;
 		}
@@ -828,7 +913,17 @@ impl DeviceClient {
 		files: Vec<DeviceFile<impl AsyncRead + Sync + Send + Unpin>>,
 		open: bool,
 	) -> Result<()> {
-		for fut in self.share_files_manual(files, open).await? {
+		self.share_files_tracked(files, open, TransferHandle::new(-1))
+			.await
+	}
+
+	pub async fn share_files_tracked(
+		&self,
+		files: Vec<DeviceFile<impl AsyncRead + Sync + Send + Unpin>>,
+		open: bool,
+		transfer: TransferHandle,
+	) -> Result<()> {
+		for fut in self.share_files_manual(files, open, &transfer).await? {
 			fut.await?;
 		}
 		Ok(())
@@ -839,7 +934,7 @@ impl DeviceClient {
 			player_list: list,
 			supports_album_art_payload: true,
 		};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn send_mpris_album_art(
@@ -848,28 +943,29 @@ impl DeviceClient {
 		url: String,
 		art: DevicePayload<impl AsyncRead + Sync + Send + Unpin>,
 	) -> Result<()> {
-		let (port, fut) = create_payload(art.buf, self.server_config.clone()).await?;
+		let (port, fut) = self
+			.create_payload(art.buf, TransferHandle::new(art.size))
+			.await?;
 		let packet = Mpris::TransferringArt {
 			player,
 			album_art_url: url,
 			transferring_album_art: true,
 		};
-		self.send_packet(make_packet_str_payload!(packet, art.size, port)?)
+		self.send_packet(make_packet_payload!(packet, art.size, port))
 			.await?;
-		fut.await;
-		Ok(())
+		fut.await
 	}
 
 	pub async fn send_mpris_info(&self, player: MprisPlayer) -> Result<()> {
 		let packet = Mpris::Info(player);
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn request_mpris_list(&self) -> Result<()> {
 		let packet = MprisRequest::List {
 			request_player_list: true,
 		};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn request_mpris_info(
@@ -883,25 +979,25 @@ impl DeviceClient {
 			request_volume: Some(true),
 			request_album_art: album_art,
 		};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn request_mpris_action(&self, action: MprisRequestAction) -> Result<()> {
 		let packet = MprisRequest::Action(action);
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn request_mousepad_action(&self, action: MousepadRequest) -> Result<()> {
-		self.send_packet(make_packet_str!(action)?).await
+		self.send_packet(make_packet!(action)).await
 	}
 
 	pub async fn send_mousepad_keyboard_state(&self) -> Result<()> {
 		let packet = MousepadKeyboardState { state: true };
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn send_mousepad_echo(&self, echo: MousepadRequest) -> Result<()> {
-		self.send_packet(make_packet_str!(echo)?).await
+		self.send_packet(make_packet!(echo)).await
 	}
 
 	pub async fn send_command_list(
@@ -911,7 +1007,7 @@ impl DeviceClient {
 		let packet = RunCommand {
 			command_list: json::to_string(command_list)?,
 		};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn request_command_list(&self) -> Result<()> {
@@ -919,7 +1015,7 @@ impl DeviceClient {
 			request_command_list: Some(true),
 			key: None,
 		};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn run_command(&self, command_id: String) -> Result<()> {
@@ -927,65 +1023,181 @@ impl DeviceClient {
 			request_command_list: None,
 			key: Some(command_id),
 		};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
 	}
 
 	pub async fn send_telephony_event(&self, event: Telephony) -> Result<()> {
-		self.send_packet(make_packet_str!(event)?).await
+		self.send_packet(make_packet!(event)).await
 	}
 
 	pub async fn send_telephony_mute_request(&self) -> Result<()> {
 		let packet = TelephonyRequestMute {};
-		self.send_packet(make_packet_str!(packet)?).await
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn send_notification(
+		&self,
+		notification: Notification,
+		icon: Option<DevicePayload<impl AsyncRead + Sync + Send + Unpin + 'static>>,
+	) -> Result<()> {
+		if let Some(icon) = icon {
+			let (port, fut) = self
+				.create_payload(icon.buf, TransferHandle::new(icon.size))
+				.await?;
+			self.send_packet(make_packet_payload!(notification, icon.size, port))
+				.await?;
+			// the icon may never be downloaded if the other side has it cached
+			tokio::spawn(fut);
+			Ok(())
+		} else {
+			self.send_packet(make_packet!(notification)).await
+		}
+	}
+
+	pub async fn cancel_notification(&self, id: String) -> Result<()> {
+		let packet = Notification {
+			id,
+			is_cancel: Some(true),
+			..Default::default()
+		};
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn request_notifications(&self) -> Result<()> {
+		let packet = NotificationRequest::Request { request: true };
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn dismiss_notification(&self, id: String) -> Result<()> {
+		let packet = NotificationRequest::Dismiss { cancel: id };
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn send_notification_action(&self, id: String, action: String) -> Result<()> {
+		let packet = NotificationAction { key: id, action };
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn request_lock_state(&self) -> Result<()> {
+		let packet = LockRequest::Request {
+			request_locked: true,
+		};
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn set_locked(&self, locked: bool) -> Result<()> {
+		let packet = LockRequest::Set { set_locked: locked };
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn send_lock_state(&self, locked: bool) -> Result<()> {
+		let packet = Lock { is_locked: locked };
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn request_contact_uids_timestamps(&self) -> Result<()> {
+		let packet = ContactsRequestAllUidsTimestamps {};
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn request_vcards_by_uid(&self, uids: Vec<String>) -> Result<()> {
+		let packet = ContactsRequestVcardsByUid { uids };
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn request_sms_conversations(&self) -> Result<()> {
+		let packet = SmsRequestConversations {};
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	/// Requests messages in a conversation. To page through a conversation, pass the date of the
+	/// oldest message recieved so far as `range_start_timestamp`.
+	pub async fn request_sms_conversation(
+		&self,
+		thread_id: i64,
+		range_start_timestamp: Option<i64>,
+		number_to_request: Option<i64>,
+	) -> Result<()> {
+		let packet = SmsRequestConversation {
+			thread_id,
+			range_start_timestamp,
+			number_to_request,
+		};
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn request_sms_attachment(
+		&self,
+		part_id: i64,
+		unique_identifier: String,
+	) -> Result<()> {
+		let packet = SmsRequestAttachment {
+			part_id,
+			unique_identifier,
+		};
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn send_sms_messages(&self, messages: Vec<SmsMessage>) -> Result<()> {
+		let packet = SmsMessages {
+			messages,
+			version: Some(SMS_MESSAGES_VERSION),
+		};
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn send_sms(
+		&self,
+		addresses: Vec<String>,
+		message_body: String,
+		sub_id: Option<i64>,
+		attachments: Vec<SmsOutgoingAttachment>,
+	) -> Result<()> {
+		let packet = SmsRequest {
+			version: SMS_REQUEST_VERSION,
+			addresses: addresses
+				.into_iter()
+				.map(|address| SmsAddress { address })
+				.collect(),
+			message_body,
+			sub_id,
+			attachments: if attachments.is_empty() {
+				None
+			} else {
+				Some(attachments)
+			},
+		};
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn send_notification_reply(
+		&self,
+		request_reply_id: String,
+		message: String,
+	) -> Result<()> {
+		let packet = NotificationReply {
+			request_reply_id,
+			message,
+		};
+		self.send_packet(make_packet!(packet)).await
+	}
+
+	pub async fn request_sftp(&self) -> Result<()> {
+		let packet = SftpRequest {
+			start_browsing: true,
+		};
+		self.send_packet(make_packet!(packet)).await
 	}
 }
 
+// packets are handled by plugins, see crate::plugins
 #[async_trait::async_trait]
 pub trait DeviceHandler {
-	async fn handle_ping(&mut self, packet: Ping);
 	async fn handle_pair_status_change(&mut self, pair_status: bool);
-	async fn handle_battery(&mut self, packet: Battery);
-	async fn handle_clipboard_content(&mut self, content: String);
-	async fn handle_find_phone(&mut self);
-	async fn handle_connectivity_report(&mut self, packet: ConnectivityReport);
-	async fn handle_presenter(&mut self, packet: Presenter);
-	async fn handle_system_volume(&mut self, packet: SystemVolume);
-	async fn handle_system_volume_request(&mut self, packet: SystemVolumeRequest);
-	async fn handle_multi_file_share(&mut self, packet: ShareRequestUpdate);
-	async fn handle_file_share(
-		&mut self,
-		packet: ShareRequestFile,
-		size: i64,
-		data: Pin<Box<dyn AsyncRead + Sync + Send>>,
-	);
-	async fn handle_url_share(&mut self, url: String);
-	async fn handle_text_share(&mut self, text: String);
-	async fn handle_mpris_player_list(&mut self, list: Vec<String>);
-	async fn handle_mpris_player_info(&mut self, player: MprisPlayer);
-	async fn handle_mpris_player_album_art(
-		&mut self,
-		player: String,
-		art: Pin<Box<dyn AsyncRead + Sync + Send>>,
-	);
-	async fn handle_mpris_player_action(&mut self, action: MprisRequestAction);
-	async fn handle_mousepad_request(&mut self, action: MousepadRequest);
-	async fn handle_mousepad_keyboard_state(&mut self, state: MousepadKeyboardState);
-	async fn handle_mousepad_echo(&mut self, echo: MousepadEcho);
-	async fn handle_command_list(&mut self, command_list: HashMap<String, RunCommandItem>);
-	async fn handle_command_request(&mut self, command_id: String);
-	async fn handle_telephony(&mut self, packet: Telephony);
-	async fn handle_telephony_mute_request(&mut self);
-
 	async fn handle_pairing_request(&mut self) -> bool;
-
-	async fn get_battery(&mut self) -> Battery;
-	async fn get_clipboard_content(&mut self) -> String;
-	async fn get_connectivity_report(&mut self) -> ConnectivityReport;
-	async fn get_system_volume(&mut self) -> Vec<SystemVolumeStream>;
-	async fn get_mpris_player_list(&mut self) -> Vec<String>;
-	async fn get_mpris_player(&mut self, player: String) -> Option<MprisPlayer>;
-	async fn get_command_list(&mut self) -> HashMap<String, RunCommandItem>;
-
+	// packets that were declared in the capabilities but no plugin handles, see
+	// PluginRegistry::register_passthrough
+	async fn handle_unknown_packet(&mut self, packet: Packet);
 	async fn handle_exit(&mut self);
 }
 
@@ -1049,3 +1261,314 @@ impl<S: AsyncRead + Sync + Send + Unpin> From<DeviceFile<S>> for DevicePayload<S
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::{net::Ipv4Addr, path::PathBuf, sync::atomic::AtomicU16};
+
+	use rcgen::KeyPair;
+	use tokio::{
+		io::{AsyncBufReadExt, Lines},
+		net::TcpListener,
+		task::JoinHandle,
+	};
+	use tokio_rustls::{server, TlsAcceptor, TlsConnector};
+
+	use super::*;
+	use crate::{
+		config::{FsConfig, FsConfigNames},
+		plugins::PluginRegistry,
+		util::generate_server_cert,
+	};
+
+	const BIGSCREEN: &str = "kdeconnect.bigscreen";
+
+	struct TestHandler(mpsc::UnboundedSender<Packet>);
+
+	#[async_trait::async_trait]
+	impl DeviceHandler for TestHandler {
+		async fn handle_pair_status_change(&mut self, _: bool) {}
+
+		// pair requests that get this far are reported as the pair packet and refused
+		async fn handle_pairing_request(&mut self) -> bool {
+			let pair = Pair {
+				pair: true,
+				timestamp: None,
+			};
+			let _ = self.0.send(make_packet!(pair));
+			false
+		}
+
+		async fn handle_unknown_packet(&mut self, packet: Packet) {
+			let _ = self.0.send(packet);
+		}
+
+		async fn handle_exit(&mut self) {}
+	}
+
+	// the other end of a device link
+	struct TestLink {
+		// the device stops once every client is gone
+		_client: DeviceClient,
+		peer: Lines<BufReader<server::TlsStream<TcpStream>>>,
+		shutdown: watch::Sender<bool>,
+		dir: PathBuf,
+	}
+
+	impl TestLink {
+		async fn send(&mut self, packet_type: &str) {
+			self.send_body(packet_type, json::json!({ "a": 1 })).await
+		}
+
+		async fn send_body(&mut self, packet_type: &str, body: json::Value) {
+			let packet = json::json!({ "id": 0, "type": packet_type, "body": body });
+			self.peer
+				.get_mut()
+				.write_all(format!("{}\n", packet).as_bytes())
+				.await
+				.unwrap();
+		}
+
+		async fn next_packet(&mut self) -> Packet {
+			let line = timeout(Duration::from_secs(5), self.peer.next_line())
+				.await
+				.unwrap()
+				.unwrap()
+				.unwrap();
+			json::from_str(&line).unwrap()
+		}
+	}
+
+	struct TestOptions {
+		paired: bool,
+		ping_interval: Option<Duration>,
+		ping_timeout: Duration,
+		// shared between links to the same device like KdeConnect does
+		pair_timestamps: Arc<Mutex<HashMap<String, u64>>>,
+	}
+
+	impl Default for TestOptions {
+		fn default() -> Self {
+			Self {
+				paired: true,
+				ping_interval: None,
+				ping_timeout: Duration::from_secs(30),
+				pair_timestamps: Arc::new(Mutex::new(HashMap::new())),
+			}
+		}
+	}
+
+	fn tls_identity(id: &str) -> (TlsIdentity, Vec<u8>) {
+		let keypair = KeyPair::generate().unwrap();
+		let cert = generate_server_cert(&keypair, id).unwrap();
+		let der = cert.der().to_vec();
+		(
+			TlsIdentity::new(cert.der().clone(), keypair.serialize_der()),
+			der,
+		)
+	}
+
+	// a device connected over loopback, the test plays the device through peer
+	async fn connect(
+		registry: &PluginRegistry<()>,
+		peer_incoming: &[&str],
+		peer_outgoing: &[&str],
+		options: TestOptions,
+	) -> (Device, TestLink) {
+		let (own_tls, own_cert) = tls_identity("own");
+		let (peer_tls, peer_cert) = tls_identity("peer");
+
+		let dir = std::env::temp_dir().join(format!(
+			"kdeconnect-device-{}",
+			hex::encode(rand::random::<[u8; 8]>())
+		));
+		let config = Arc::new(
+			FsConfig::new(dir.clone(), FsConfigNames::default())
+				.await
+				.unwrap(),
+		);
+		// unpaired devices can only pair
+		if options.paired {
+			config
+				.store_device_config(&DeviceConfig {
+					id: "peer".to_string(),
+					name: "peer".to_string(),
+					device_type: DeviceType::Phone,
+					certificate: Some(peer_cert.clone()),
+					last_address: None,
+				})
+				.await
+				.unwrap();
+		}
+
+		let identity = Identity {
+			device_id: "peer".to_string(),
+			device_name: "peer".to_string(),
+			device_type: DeviceType::Phone,
+			incoming_capabilities: peer_incoming.iter().map(|x| x.to_string()).collect(),
+			outgoing_capabilities: peer_outgoing.iter().map(|x| x.to_string()).collect(),
+			protocol_version: 8,
+			tcp_port: None,
+		};
+		let capabilities = DeviceCapabilities::new(
+			&identity,
+			&registry.incoming_capabilities(),
+			&registry.outgoing_capabilities(),
+		);
+
+		let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+		let (stream, peer) = tokio::join!(
+			TcpStream::connect(listener.local_addr().unwrap()),
+			listener.accept()
+		);
+		let connector = TlsConnector::from(own_tls.client_config(Some(peer_cert)).unwrap());
+		let acceptor = TlsAcceptor::from(peer_tls.server_config(Some(own_cert)).unwrap());
+		let (stream, peer) = tokio::join!(
+			connector.connect("peer".try_into().unwrap(), BufReader::new(stream.unwrap())),
+			acceptor.accept(peer.unwrap().0)
+		);
+
+		let (disconnected, _) = mpsc::unbounded_channel();
+		let (shutdown, shutdown_rx) = watch::channel(false);
+		let settings = LinkSettings {
+			pairing_timeout: Duration::from_secs(30),
+			pair_timestamp_tolerance: Duration::from_secs(30),
+			write_timeout: Duration::from_secs(5),
+			ping_interval: options.ping_interval,
+			ping_timeout: options.ping_timeout,
+			max_packet_size: 1024 * 1024,
+			send_queue_size: 16,
+			payload: PayloadConfig {
+				accept_timeout: Duration::from_secs(5),
+				idle_timeout: Duration::from_secs(5),
+				ports: 60000..=64000,
+				ephemeral_fallback: true,
+				next_port: Arc::new(AtomicU16::new(0)),
+			},
+		};
+		let shared = LinkShared {
+			config_provider: config,
+			tls_identity: own_tls,
+			connected_clients: Arc::new(Mutex::new(Vec::new())),
+			pair_timestamps: options.pair_timestamps,
+			disconnected,
+			shutdown: shutdown_rx,
+		};
+		let (device, client) = create_device(
+			identity,
+			8,
+			stream.unwrap().into(),
+			capabilities,
+			settings,
+			shared,
+		)
+		.await
+		.unwrap();
+
+		let link = TestLink {
+			_client: client,
+			peer: BufReader::new(peer.unwrap()).lines(),
+			shutdown,
+			dir,
+		};
+		(device, link)
+	}
+
+	fn spawn(
+		mut device: Device,
+		registry: &PluginRegistry<()>,
+	) -> (JoinHandle<Result<()>>, mpsc::UnboundedReceiver<Packet>) {
+		let (tx, rx) = mpsc::unbounded_channel();
+		let plugins = registry.create_plugins(&());
+		let task =
+			tokio::spawn(async move { device.task(Box::new(TestHandler(tx)), plugins).await });
+		(task, rx)
+	}
+
+	#[tokio::test]
+	async fn passthrough_packet_reaches_handler() {
+		let mut registry = PluginRegistry::<()>::new();
+		registry.register_passthrough(vec![BIGSCREEN.to_string()], vec![]);
+		assert_eq!(registry.incoming_capabilities(), [BIGSCREEN]);
+		let (device, mut link) =
+			connect(&registry, &[], &[BIGSCREEN], TestOptions::default()).await;
+		let (task, mut rx) = spawn(device, &registry);
+
+		link.send("kdeconnect.undeclared").await;
+		link.send(BIGSCREEN).await;
+		// the undeclared packet was sent first and dropped
+		let packet = timeout(Duration::from_secs(5), rx.recv())
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(packet.packet_type, BIGSCREEN);
+		assert_eq!(packet.body["a"], 1);
+
+		link.shutdown.send_replace(true);
+		task.await.unwrap().unwrap();
+		tokio::fs::remove_dir_all(link.dir).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn unanswered_ping_closes_link() {
+		let mut registry = PluginRegistry::<()>::new();
+		registry.register_passthrough(vec![], vec![Ping::TYPE.to_string()]);
+		let options = TestOptions {
+			ping_interval: Some(Duration::from_millis(100)),
+			ping_timeout: Duration::from_millis(300),
+			..Default::default()
+		};
+		let (device, mut link) = connect(&registry, &[Ping::TYPE], &[], options).await;
+		let (task, _) = spawn(device, &registry);
+
+		// anything from the device counts as an answer
+		for _ in 0..3 {
+			assert_eq!(link.next_packet().await.packet_type, Ping::TYPE);
+			link.send("kdeconnect.undeclared").await;
+		}
+		assert!(!task.is_finished());
+
+		let ret = timeout(Duration::from_secs(5), task)
+			.await
+			.unwrap()
+			.unwrap();
+		assert!(matches!(ret, Err(KdeConnectError::LinkTimedOut)));
+		tokio::fs::remove_dir_all(link.dir).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn replayed_pair_request_is_refused_after_reconnect() {
+		let registry = PluginRegistry::<()>::new();
+		let pair_timestamps = Arc::new(Mutex::new(HashMap::new()));
+		let pair = json::json!({ "pair": true, "timestamp": get_time_secs() });
+
+		let options = TestOptions {
+			paired: false,
+			pair_timestamps: pair_timestamps.clone(),
+			..Default::default()
+		};
+		let (device, mut link) = connect(&registry, &[], &[], options).await;
+		let (task, mut rx) = spawn(device, &registry);
+		link.send_body(Pair::TYPE, pair.clone()).await;
+		assert_eq!(link.next_packet().await.body["pair"], false);
+		assert_eq!(rx.recv().await.unwrap().packet_type, Pair::TYPE);
+		link.shutdown.send_replace(true);
+		task.await.unwrap().unwrap();
+		tokio::fs::remove_dir_all(link.dir).await.unwrap();
+
+		// the same request on a new link is refused without asking
+		let options = TestOptions {
+			paired: false,
+			pair_timestamps,
+			..Default::default()
+		};
+		let (device, mut link) = connect(&registry, &[], &[], options).await;
+		let (task, mut rx) = spawn(device, &registry);
+		link.send_body(Pair::TYPE, pair).await;
+		assert_eq!(link.next_packet().await.body["pair"], false);
+		assert!(rx.try_recv().is_err());
+		link.shutdown.send_replace(true);
+		task.await.unwrap().unwrap();
+		tokio::fs::remove_dir_all(link.dir).await.unwrap();
+	}
+}
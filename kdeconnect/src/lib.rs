@@ -1,49 +1,58 @@
 #![feature(once_cell_try, let_chains, duration_constructors)]
+pub mod commands;
 pub mod config;
 pub mod device;
 pub mod packets;
+pub mod plugins;
+mod send_queue;
+pub mod sftp;
+pub mod storage;
+pub mod transfer;
 mod util;
+pub mod vcard;
 
 use std::{
 	collections::HashMap,
+	future::Future,
 	io,
-	net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
-	sync::Arc,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+	ops::RangeInclusive,
+	path::PathBuf,
+	sync::{atomic::AtomicU16, Arc},
 	time::Duration,
 };
 
 use config::ConfigProvider;
-use device::{create_device, Device, DeviceClient};
+use device::{
+	create_device, Device, DeviceCapabilities, DeviceClient, DeviceConfig, LinkSettings, LinkShared,
+};
 use packets::{DeviceType, Identity, Packet, PacketType, PROTOCOL_VERSION};
-use util::NoCertificateVerification;
+use plugins::PluginRegistry;
+use transfer::PayloadConfig;
+use util::{bind_tcp_v6, bind_udp_v6, read_line_unbuffered, LimitedLines, TlsIdentity};
 
-use log::{debug, error, info};
-use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use if_addrs::{IfAddr, Ifv4Addr, Interface};
+use log::{debug, error, info, warn};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
 use rcgen::KeyPair;
 use thiserror::Error;
 use tokio::{
-	io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+	io::{AsyncWriteExt, BufReader},
 	net::{TcpListener, TcpStream, UdpSocket},
 	select,
-	sync::{mpsc, oneshot, Mutex},
-	time::{interval, sleep, MissedTickBehavior},
+	sync::{mpsc, oneshot, watch, Mutex, RwLock},
+	time::{interval, sleep, sleep_until, timeout, Instant, MissedTickBehavior},
 };
 
 use serde_json as json;
-use tokio_rustls::{
-	rustls::{
-		crypto::ring::default_provider,
-		pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
-		ClientConfig, ServerConfig,
-	},
-	TlsAcceptor, TlsConnector,
-};
-use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
+use tokio_rustls::{rustls::pki_types::CertificateDer, TlsAcceptor, TlsConnector, TlsStream};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
 
 #[derive(Error, Debug)]
 pub enum KdeConnectError {
 	#[error(transparent)]
-	Io(#[from] io::Error),
+	Io(io::Error),
 	#[error(transparent)]
 	Mdns(#[from] mdns_sd::Error),
 	#[error(transparent)]
@@ -70,15 +79,65 @@ pub enum KdeConnectError {
 	NoPayloadTransferPortFound,
 	#[error("No filename")]
 	NoFileName,
+	#[error("Invalid vCard")]
+	InvalidVCard,
+	#[error("Sftp error: {0}")]
+	SftpError(String),
+	#[error("Invalid sftp info")]
+	InvalidSftpInfo,
+	#[error("Failed to find port for sftp server")]
+	NoSftpPortFound,
+	#[error("Unknown command")]
+	UnknownCommand,
+	#[error("Command timed out")]
+	CommandTimedOut,
+	#[error("Packet type {0:?} is not supported by the device")]
+	UnsupportedPacketType(String),
+	#[error("Peer sent a line longer than {0} bytes")]
+	LineTooLong(usize),
+	#[error("Peer did not send its identity or finish the handshake in time")]
+	IdentityTimedOut,
+	#[error("Device link timed out")]
+	LinkTimedOut,
+	#[error("No usable network interfaces")]
+	NoUsableInterfaces,
+	#[error("Payload transfer cancelled")]
+	TransferCancelled,
+	#[error("Payload transfer timed out")]
+	TransferTimedOut,
+	#[error("Payload ended after {1} of {0} bytes")]
+	PayloadTruncated(u64, u64),
+	#[error("Payload is longer than {0} bytes")]
+	PayloadOversized(u64),
+	#[error("Directory {0:?} is not allowed for storing files")]
+	DirectoryNotAllowed(PathBuf),
+	#[error("Device is over its storage quota")]
+	StorageQuotaExceeded,
 	#[error("Other")]
 	Other,
 
+	#[error("Identity sent over TLS does not match the identity sent before TLS")]
+	InvalidIdentity,
+
 	#[error("Device rejected pair")]
 	DeviceRejectedPair,
+	#[error("Device did not respond to pair")]
+	DevicePairTimedOut,
 	#[error("Already paired")]
 	DeviceAlreadyPaired,
 }
 
+impl From<io::Error> for KdeConnectError {
+	// payload readers can only fail with io errors, the typed error inside is returned as is
+	fn from(err: io::Error) -> Self {
+		if err.get_ref().is_some_and(|x| x.is::<KdeConnectError>()) {
+			// checked above
+			return *err.into_inner().unwrap().downcast().unwrap();
+		}
+		Self::Io(err)
+	}
+}
+
 impl<T> From<mpsc::error::SendError<T>> for KdeConnectError {
 	fn from(_: mpsc::error::SendError<T>) -> Self {
 		Self::ChannelSendError
@@ -93,39 +152,117 @@ impl From<oneshot::error::RecvError> for KdeConnectError {
 
 type Result<T> = std::result::Result<T, KdeConnectError>;
 
-const KDECONNECT_PORT: u16 = 1716;
+pub const KDECONNECT_PORT: u16 = 1716;
+const DEFAULT_PAIRING_TIMEOUT: Duration = Duration::from_secs(30);
+// kdeconnect-kde and kdeconnect-android allow half an hour of clock difference
+const DEFAULT_PAIR_TIMESTAMP_TOLERANCE: Duration = Duration::from_secs(1800);
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(300);
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_LINK_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_IDENTITY_SIZE: usize = 8192;
+// sms and contacts sync can send a lot in one packet
+const DEFAULT_MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_IDENTITY_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_HANDSHAKES: usize = 16;
+const DEFAULT_SEND_QUEUE_SIZE: usize = 64;
+const DEFAULT_PAYLOAD_ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_PAYLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_PAYLOAD_PORTS: RangeInclusive<u16> = 60000..=64000;
+// devices wait here until the integrator takes them from the stream
+const NEW_DEVICE_QUEUE_SIZE: usize = 16;
+// v6 has no broadcast, identities are sent to the link local all nodes group instead
+const IPV6_ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
 
 enum KdeConnectAction {
 	BroadcastIdentity(oneshot::Sender<Result<()>>),
+	ConnectTo(SocketAddr, oneshot::Sender<Result<()>>),
+	AddCustomDevice(SocketAddr, oneshot::Sender<Result<()>>),
+	RemoveCustomDevice(SocketAddr, oneshot::Sender<Result<()>>),
+	CustomDevices(oneshot::Sender<Vec<SocketAddr>>),
+	Rename(String, oneshot::Sender<Result<()>>),
+	Shutdown(oneshot::Sender<Result<()>>),
 }
 
 pub struct KdeConnect {
 	pub device_type: DeviceType,
-	pub device_name: String,
 	pub device_id: String,
 	pub device_incoming_capabilities: Vec<String>,
 	pub device_outgoing_capabilities: Vec<String>,
+	/// How long to wait for either side to answer a pair request.
+	pub pairing_timeout: Duration,
+	/// Pair requests with a timestamp further than this from the current time are rejected.
+	pub pair_timestamp_tolerance: Duration,
+	/// Longest wait between attempts to reach a disconnected paired device.
+	pub reconnect_max_backoff: Duration,
+	/// Names of the interfaces to discover devices on, all interfaces are used if empty.
+	pub allowed_interfaces: Vec<String>,
+	/// Names of interfaces to never discover devices on, such as docker bridges or vpns.
+	pub denied_interfaces: Vec<String>,
+	/// TCP keepalive of device links, `None` leaves it to the os.
+	pub link_keepalive: Option<LinkKeepalive>,
+	/// Device links are closed when sending a packet takes longer than this.
+	pub link_write_timeout: Duration,
+	/// Pings devices that have been silent for this long so dead links are noticed sooner. Most
+	/// devices show a notification for every ping and don't answer it, so this is off by default.
+	pub ping_interval: Option<Duration>,
+	/// Links are closed when nothing arrives from a device this long after it was pinged.
+	pub ping_timeout: Duration,
+	/// Longest identity accepted from a device, in bytes.
+	pub max_identity_size: usize,
+	/// Longest packet accepted from a connected device, in bytes.
+	pub max_packet_size: usize,
+	/// How long a connecting device has to send its identity. Also limits connecting to a device
+	/// and the TLS handshake.
+	pub identity_timeout: Duration,
+	/// Devices that can be connecting at once on each listener, others wait until one is done.
+	pub max_handshakes: usize,
+	/// Packets that can wait to be sent to a device before sending blocks. Mousepad and
+	/// presenter movements are added together instead of taking up space.
+	pub send_queue_size: usize,
+	/// How long a device has to connect to a payload being sent to it.
+	pub payload_accept_timeout: Duration,
+	/// Payload transfers fail when no data is sent or recieved for this long.
+	pub payload_idle_timeout: Duration,
+	/// Ports payloads are offered on.
+	pub payload_ports: RangeInclusive<u16>,
+	/// Offer payloads on a port picked by the os when every port in `payload_ports` is taken.
+	pub payload_ephemeral_fallback: bool,
+
+	// can be changed while running
+	device_name: RwLock<String>,
+	next_payload_port: Arc<AtomicU16>,
 
 	udp_socket: UdpSocket,
+	udp_socket_v6: Option<UdpSocket>,
 	mdns: ServiceDaemon,
 
-	server_tls_config: Arc<ServerConfig>,
-	client_tls_config: Arc<ClientConfig>,
+	tls_identity: TlsIdentity,
 	config: Arc<dyn ConfigProvider + Sync + Send>,
+	// devices added by address for networks where discovery doesn't work
+	custom_devices: Mutex<Vec<SocketAddr>>,
 
 	connected_clients: Arc<Mutex<Vec<String>>>,
+	// last accepted pair timestamp of every device, kept across links so reconnecting doesn't
+	// allow replaying a pair request
+	pair_timestamps: Arc<Mutex<HashMap<String, u64>>>,
+	disconnected_tx: mpsc::UnboundedSender<DeviceConfig>,
+	disconnected_rx: Mutex<mpsc::UnboundedReceiver<DeviceConfig>>,
+	// device links are closed when set
+	shutdown_tx: watch::Sender<bool>,
 
-	new_device_tx: mpsc::UnboundedSender<(Device, DeviceClient)>,
+	new_device_tx: mpsc::Sender<(Device, DeviceClient)>,
 	client_rx: Mutex<mpsc::UnboundedReceiver<KdeConnectAction>>,
 }
 
 impl KdeConnect {
-	pub async fn new(
+	// capabilities are taken from the plugins
+	pub async fn new<C>(
 		device_id: String,
 		device_name: String,
 		device_type: DeviceType,
-		incoming_caps: Vec<String>,
-		outgoing_caps: Vec<String>,
+		plugins: &PluginRegistry<C>,
 		config: Arc<dyn ConfigProvider + Sync + Send>,
 	) -> Result<(
 		Self,
@@ -135,6 +272,10 @@ impl KdeConnect {
 		let udp_socket =
 			UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, KDECONNECT_PORT)).await?;
 		udp_socket.set_broadcast(true)?;
+		// not every network has v6
+		let udp_socket_v6 = bind_udp_v6(KDECONNECT_PORT)
+			.inspect_err(|err| warn!("failed to bind udp socket over ipv6: {:?}", err))
+			.ok();
 		let mdns = ServiceDaemon::new()?;
 
 		let keypair = match config
@@ -159,31 +300,21 @@ impl KdeConnect {
 			}
 		};
 
-		let verifier = Arc::new(NoCertificateVerification::new(default_provider()));
-
-		// FIXME Verify certs
-		let server_tls_config = Arc::new(
-			ServerConfig::builder()
-				.with_client_cert_verifier(verifier.clone())
-				.with_single_cert(
-					vec![cert.clone()],
-					PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(keypair.serialize_der())),
-				)?,
-		);
+		let tls_identity = TlsIdentity::new(cert, keypair.serialize_der());
 
-		// FIXME Verify certs
-		let client_tls_config = Arc::new(
-			ClientConfig::builder()
-				.dangerous()
-				.with_custom_certificate_verifier(verifier.clone())
-				.with_client_auth_cert(
-					vec![cert.clone()],
-					PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(keypair.serialize_der())),
-				)?,
-		);
+		// the stored devices would be replaced by the next change if they couldn't be read
+		let custom_devices = match config.retrieve_custom_devices().await {
+			Ok(custom_devices) => custom_devices,
+			Err(KdeConnectError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+				debug!("no stored custom devices");
+				Vec::new()
+			}
+			Err(err) => return Err(err),
+		};
 
-		let (new_device_tx, new_device_rx) = mpsc::unbounded_channel();
+		let (new_device_tx, new_device_rx) = mpsc::channel(NEW_DEVICE_QUEUE_SIZE);
 		let (client_tx, client_rx) = mpsc::unbounded_channel();
+		let (disconnected_tx, disconnected_rx) = mpsc::unbounded_channel();
 
 		info!(
 			"initialized kde connect device id: {:?} name: {:?} type: {:?}",
@@ -193,32 +324,122 @@ impl KdeConnect {
 		Ok((
 			Self {
 				device_id,
-				device_name,
 				device_type,
-				device_incoming_capabilities: incoming_caps,
-				device_outgoing_capabilities: outgoing_caps,
+				device_incoming_capabilities: plugins.incoming_capabilities(),
+				device_outgoing_capabilities: plugins.outgoing_capabilities(),
+				pairing_timeout: DEFAULT_PAIRING_TIMEOUT,
+				pair_timestamp_tolerance: DEFAULT_PAIR_TIMESTAMP_TOLERANCE,
+				reconnect_max_backoff: DEFAULT_RECONNECT_MAX_BACKOFF,
+				allowed_interfaces: Vec::new(),
+				denied_interfaces: Vec::new(),
+				link_keepalive: Some(LinkKeepalive::default()),
+				link_write_timeout: DEFAULT_LINK_WRITE_TIMEOUT,
+				ping_interval: None,
+				ping_timeout: DEFAULT_PING_TIMEOUT,
+				max_identity_size: DEFAULT_MAX_IDENTITY_SIZE,
+				max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+				identity_timeout: DEFAULT_IDENTITY_TIMEOUT,
+				max_handshakes: DEFAULT_MAX_HANDSHAKES,
+				send_queue_size: DEFAULT_SEND_QUEUE_SIZE,
+				payload_accept_timeout: DEFAULT_PAYLOAD_ACCEPT_TIMEOUT,
+				payload_idle_timeout: DEFAULT_PAYLOAD_IDLE_TIMEOUT,
+				payload_ports: DEFAULT_PAYLOAD_PORTS,
+				payload_ephemeral_fallback: true,
+
+				device_name: RwLock::new(device_name),
+				// payload_ports can still be changed, bind starts at the start of the range for
+				// any port outside of it
+				next_payload_port: Arc::new(AtomicU16::new(0)),
 
 				udp_socket,
+				udp_socket_v6,
 				mdns,
 
 				config,
-				server_tls_config,
-				client_tls_config,
+				tls_identity,
+				custom_devices: Mutex::new(custom_devices),
 
 				connected_clients: Arc::new(Mutex::new(Vec::new())),
+				pair_timestamps: Arc::new(Mutex::new(HashMap::new())),
+				disconnected_tx,
+				disconnected_rx: Mutex::new(disconnected_rx),
+				shutdown_tx: watch::channel(false).0,
 
 				new_device_tx,
 				client_rx: Mutex::new(client_rx),
 			},
 			KdeConnectClient { client_tx },
-			UnboundedReceiverStream::new(new_device_rx),
+			ReceiverStream::new(new_device_rx),
 		))
 	}
 
-	fn make_identity(&self, tcp_port: Option<u16>) -> Packet {
+	pub async fn device_name(&self) -> String {
+		self.device_name.read().await.clone()
+	}
+
+	// read when the server starts, changes apply once it is restarted
+	fn link_settings(&self) -> LinkSettings {
+		LinkSettings {
+			pairing_timeout: self.pairing_timeout,
+			pair_timestamp_tolerance: self.pair_timestamp_tolerance,
+			write_timeout: self.link_write_timeout,
+			ping_interval: self.ping_interval,
+			ping_timeout: self.ping_timeout,
+			max_packet_size: self.max_packet_size,
+			send_queue_size: self.send_queue_size,
+			payload: PayloadConfig {
+				accept_timeout: self.payload_accept_timeout,
+				idle_timeout: self.payload_idle_timeout,
+				ports: self.payload_ports.clone(),
+				ephemeral_fallback: self.payload_ephemeral_fallback,
+				next_port: self.next_payload_port.clone(),
+			},
+		}
+	}
+
+	fn link_shared(&self) -> LinkShared {
+		LinkShared {
+			config_provider: self.config.clone(),
+			tls_identity: self.tls_identity.clone(),
+			connected_clients: self.connected_clients.clone(),
+			pair_timestamps: self.pair_timestamps.clone(),
+			disconnected: self.disconnected_tx.clone(),
+			shutdown: self.shutdown_tx.subscribe(),
+		}
+	}
+
+	// hands a device to the integrator once tls is up and identities are exchanged
+	async fn add_device(
+		&self,
+		identity: Identity,
+		protocol_version: usize,
+		stream: TlsStream<BufReader<TcpStream>>,
+		settings: &LinkSettings,
+	) -> Result<()> {
+		let capabilities = DeviceCapabilities::new(
+			&identity,
+			&self.device_incoming_capabilities,
+			&self.device_outgoing_capabilities,
+		);
+		let device_tuple = create_device(
+			identity,
+			protocol_version,
+			stream,
+			capabilities,
+			settings.clone(),
+			self.link_shared(),
+		)
+		.await?;
+		self.new_device_tx
+			.send(device_tuple)
+			.await
+			.map_err(KdeConnectError::from)
+	}
+
+	async fn make_identity(&self, tcp_port: Option<u16>) -> Packet {
 		let ident = Identity {
 			device_id: self.device_id.clone(),
-			device_name: self.device_name.clone(),
+			device_name: self.device_name().await,
 			device_type: self.device_type,
 			protocol_version: PROTOCOL_VERSION,
 			incoming_capabilities: self.device_incoming_capabilities.clone(),
@@ -228,167 +449,481 @@ impl KdeConnect {
 		make_packet!(ident)
 	}
 
+	// paired devices must present the certificate stored when pairing
+	async fn get_paired_cert(&self, device_id: &str) -> Option<Vec<u8>> {
+		self.config
+			.retrieve_device_config(device_id)
+			.await
+			.ok()
+			.and_then(|x| x.certificate)
+	}
+
+	// runs until KdeConnectClient::shutdown is called or an error happens, and can be started
+	// again afterwards
 	pub async fn start_server(&self) -> Result<()> {
+		self.shutdown_tx.send_replace(false);
+		self.select_mdns_interfaces()?;
 		let fullname = self.publish_mdns().await?;
 		info!("published mdns service");
+		let settings = self.link_settings();
+		let mut shutdown = None;
 		let ret = select! {
-			x = self.listen_on_udp() => x,
+			x = self.listen_on_udp(&settings) => x,
 			x = self.send_on_udp() => x,
-			x = self.listen_on_tcp() => x,
+			x = self.listen_on_tcp(&settings) => x,
 			x = self.discover_mdns() => x,
-			_ = self.respond_to_client() => Ok(()),
+			x = self.reconnect_devices() => x,
+			x = self.respond_to_client() => {
+				shutdown = x;
+				Ok(())
+			}
 		};
-		self.mdns.unregister(&fullname)?;
+		// devices can't be reached without the server
+		self.shutdown_tx.send_replace(true);
+		let unregistered = self.unregister_mdns(&fullname).await;
+		if let Some(respond) = shutdown {
+			let _ = respond.send(Ok(()));
+		}
+		ret.and(unregistered)
+	}
+
+	async fn unregister_mdns(&self, fullname: &str) -> Result<()> {
+		// wait for the goodbye to be sent
+		let _ = self.mdns.unregister(fullname)?.recv_async().await;
 		info!("unpublished mdns service");
-		ret
+		Ok(())
+	}
+
+	async fn rename(&self, device_name: String) -> Result<()> {
+		*self.device_name.write().await = device_name;
+		self.publish_mdns().await?;
+		self.send_identity_once().await
 	}
 
-	async fn respond_to_client(&self) {
+	// returns the responder of a shutdown request
+	async fn respond_to_client(&self) -> Option<oneshot::Sender<Result<()>>> {
 		while let Some(evt) = self.client_rx.lock().await.recv().await {
 			use KdeConnectAction as A;
-			let _ = match evt {
-				A::BroadcastIdentity(respond) => respond.send(self.send_identity_once().await),
-			};
+			// the client might have stopped waiting
+			match evt {
+				A::BroadcastIdentity(respond) => {
+					let _ = respond.send(self.send_identity_once().await);
+				}
+				A::ConnectTo(addr, respond) => {
+					let _ = respond.send(self.send_identity_to(addr).await);
+				}
+				A::AddCustomDevice(addr, respond) => {
+					let _ = respond.send(self.add_custom_device(addr).await);
+				}
+				A::RemoveCustomDevice(addr, respond) => {
+					let _ = respond.send(self.remove_custom_device(addr).await);
+				}
+				A::CustomDevices(respond) => {
+					let _ = respond.send(self.custom_devices.lock().await.clone());
+				}
+				A::Rename(device_name, respond) => {
+					let _ = respond.send(self.rename(device_name).await);
+				}
+				A::Shutdown(respond) => return Some(respond),
+			}
+		}
+		None
+	}
+
+	// protocol v8 sends the identity again once tls is established, the one sent before is
+	// untrusted and only used to set up tls
+	async fn exchange_identity(
+		&self,
+		stream: &mut TlsStream<BufReader<TcpStream>>,
+		identity: Identity,
+	) -> Result<(Identity, usize)> {
+		let protocol_version = identity.protocol_version.min(PROTOCOL_VERSION);
+		if protocol_version < 8 {
+			return Ok((identity, protocol_version));
+		}
+
+		let own_identity = json::to_string(&self.make_identity(None).await)? + "\n";
+		stream.write_all(own_identity.as_bytes()).await?;
+		stream.flush().await?;
+
+		let identity_line = read_line_unbuffered(stream, self.max_identity_size);
+		let identity_line = timeout(self.identity_timeout, identity_line)
+			.await
+			.map_err(|_| KdeConnectError::IdentityTimedOut)??;
+		let packet: Packet = json::from_str(&identity_line)?;
+		if packet.packet_type != Identity::TYPE {
+			return Err(KdeConnectError::InvalidIdentity);
+		}
+		let tls_identity: Identity = json::from_value(packet.body)?;
+		if tls_identity.device_id != identity.device_id {
+			return Err(KdeConnectError::InvalidIdentity);
 		}
+
+		let protocol_version = tls_identity.protocol_version.min(PROTOCOL_VERSION);
+		Ok((tls_identity, protocol_version))
 	}
 
-	async fn listen_on_tcp(&self) -> Result<()> {
+	async fn listen_on_tcp(&self, settings: &LinkSettings) -> Result<()> {
 		let tcp_listener =
 			TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, KDECONNECT_PORT)).await?;
 		info!("listening on tcp");
-		while let Ok((stream, _)) = tcp_listener.accept().await {
-			let mut stream = BufReader::new(stream);
-			let mut identity = String::new();
-			stream.read_line(&mut identity).await?;
-			if let Ok(packet) = json::from_str::<Packet>(&identity)
-				&& let Ok(identity) = json::from_value::<Identity>(packet.body)
-			{
-				if self
-					.connected_clients
-					.lock()
-					.await
-					.contains(&identity.device_id)
-				{
-					debug!("ignoring reconnect from client {:?}", identity.device_id);
-					continue;
+		match bind_tcp_v6(KDECONNECT_PORT, true) {
+			Ok(tcp_listener_v6) => {
+				info!("listening on tcp over ipv6");
+				select! {
+					x = self.accept_on_tcp(tcp_listener, settings) => x,
+					x = self.accept_on_tcp(tcp_listener_v6, settings) => x,
 				}
+			}
+			Err(err) => {
+				warn!("failed to listen on tcp over ipv6: {:?}", err);
+				self.accept_on_tcp(tcp_listener, settings).await
+			}
+		}
+	}
 
-				let dev_id = identity.device_id.clone();
-
-				let ret = async {
-					self.connected_clients
-						.lock()
-						.await
-						.push(identity.device_id.clone());
-
-					// dummy dns name, it doesn't get checked anyway
-					let stream = TlsConnector::from(self.client_tls_config.clone())
-						.connect(identity.device_id.clone().try_into()?, stream)
-						.await?;
-
-					info!("new device via tcp: {:#?}", identity);
-
-					let device_tuple = create_device(
-						identity,
-						self.config.clone(),
-						stream.into(),
-						self.connected_clients.clone(),
-						self.server_tls_config.clone(),
-						self.client_tls_config.clone(),
-					)
-					.await?;
-
-					self.new_device_tx
-						.send(device_tuple)
-						.map_err(KdeConnectError::from)
-				}
-				.await;
-				if let Err(err) = ret {
-					error!("error while accepting device via tcp: {:?}", err);
-					self.connected_clients.lock().await.retain(|x| *x != dev_id);
+	// anyone on the network can connect, so the untrusted identity is read with limits
+	async fn read_identity(&self, stream: &mut BufReader<TcpStream>) -> Result<String> {
+		let mut lines = LimitedLines::new(stream, self.max_identity_size);
+		timeout(self.identity_timeout, lines.next_line())
+			.await
+			.map_err(|_| KdeConnectError::IdentityTimedOut)??
+			.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+	}
+
+	// a stalled device mustn't hold one of the handshake slots forever
+	async fn handshake<T>(&self, fut: impl Future<Output = io::Result<T>>) -> Result<T> {
+		Ok(timeout(self.identity_timeout, fut)
+			.await
+			.map_err(|_| KdeConnectError::IdentityTimedOut)??)
+	}
+
+	async fn accept_on_tcp(
+		&self,
+		tcp_listener: TcpListener,
+		settings: &LinkSettings,
+	) -> Result<()> {
+		let mut handshakes = FuturesUnordered::new();
+		loop {
+			let accepting = handshakes.len() < self.max_handshakes.max(1);
+			select! {
+				x = tcp_listener.accept(), if accepting => {
+					let Ok((stream, addr)) = x else {
+						break;
+					};
+					handshakes.push(self.accept_device(stream, addr, settings));
 				}
+				Some(()) = handshakes.next() => {}
 			}
 		}
 		Ok(())
 	}
 
-	async fn listen_on_udp(&self) -> Result<()> {
-		info!("listening on udp");
-		loop {
-			let mut buf = vec![0u8; 8192];
-			let (len, mut addr) = self.udp_socket.recv_from(&mut buf).await?;
-			let packet: Packet = json::from_slice(&buf[..len])?;
-			if let Ok(identity) = json::from_value::<Identity>(packet.body)
-				&& identity.device_id != self.device_id
-				&& let Some(tcp_port) = identity.tcp_port
-			{
-				if self
-					.connected_clients
-					.lock()
-					.await
-					.contains(&identity.device_id)
-				{
-					debug!("ignoring reconnect to client {:?}", identity.device_id);
-					continue;
-				}
+	async fn accept_device(&self, stream: TcpStream, addr: SocketAddr, settings: &LinkSettings) {
+		self.set_link_keepalive(&stream);
+		let mut stream = BufReader::new(stream);
+		let identity = match self.read_identity(&mut stream).await {
+			Ok(identity) => identity,
+			Err(err) => {
+				debug!("failed to read identity from {:?}: {:?}", addr, err);
+				return;
+			}
+		};
+		let Ok(packet) = json::from_str::<Packet>(&identity) else {
+			return;
+		};
+		let Ok(identity) = json::from_value::<Identity>(packet.body) else {
+			return;
+		};
+		if !self.claim_client(&identity.device_id).await {
+			debug!("ignoring reconnect from client {:?}", identity.device_id);
+			return;
+		}
 
-				let dev_id = identity.device_id.clone();
+		let dev_id = identity.device_id.clone();
 
-				let ret = async {
-					addr.set_port(tcp_port);
+		let ret = async {
+			let client_config = self
+				.tls_identity
+				.client_config(self.get_paired_cert(&identity.device_id).await)?;
 
-					self.connected_clients
-						.lock()
-						.await
-						.push(identity.device_id.clone());
+			// dummy dns name, it doesn't get checked anyway
+			let stream = self
+				.handshake(
+					TlsConnector::from(client_config)
+						.connect(identity.device_id.clone().try_into()?, stream),
+				)
+				.await?;
 
-					let mut stream = BufReader::new(TcpStream::connect(addr).await?);
-					let own_identity = json::to_string(&self.make_identity(None))? + "\n";
-					stream.write_all(own_identity.as_bytes()).await?;
+			let mut stream = stream.into();
+			let (identity, protocol_version) =
+				self.exchange_identity(&mut stream, identity).await?;
+			info!("new device via tcp: {:#?}", identity);
 
-					let stream = TlsAcceptor::from(self.server_tls_config.clone())
-						.accept(stream)
-						.await?;
+			self.add_device(identity, protocol_version, stream, settings)
+				.await
+		}
+		.await;
+		if let Err(err) = ret {
+			error!("error while accepting device via tcp: {:?}", err);
+			self.connected_clients.lock().await.retain(|x| *x != dev_id);
+		}
+	}
 
-					info!("new device discovered through udp: {:#?}", identity);
+	// checked and added under one lock so two links to the same device can't both be set up
+	async fn claim_client(&self, device_id: &str) -> bool {
+		let mut connected_clients = self.connected_clients.lock().await;
+		if connected_clients.iter().any(|x| x == device_id) {
+			return false;
+		}
+		connected_clients.push(device_id.to_string());
+		true
+	}
 
-					let device_tuple = create_device(
-						identity,
-						self.config.clone(),
-						stream.into(),
-						self.connected_clients.clone(),
-						self.server_tls_config.clone(),
-						self.client_tls_config.clone(),
-					)
-					.await?;
+	async fn listen_on_udp(&self, settings: &LinkSettings) -> Result<()> {
+		info!("listening on udp");
+		match self.udp_socket_v6.as_ref() {
+			Some(udp_socket_v6) => select! {
+				x = self.recieve_on_udp(&self.udp_socket, settings) => x,
+				x = self.recieve_on_udp(udp_socket_v6, settings) => x,
+			},
+			None => self.recieve_on_udp(&self.udp_socket, settings).await,
+		}
+	}
 
-					self.new_device_tx
-						.send(device_tuple)
-						.map_err(KdeConnectError::from)
-				}
-				.await;
-				if let Err(err) = ret {
-					error!(
-						"error while connecting to device discovered through udp: {:?}",
-						err
-					);
-					self.connected_clients.lock().await.retain(|x| *x != dev_id);
+	async fn recieve_on_udp(&self, udp_socket: &UdpSocket, settings: &LinkSettings) -> Result<()> {
+		let mut handshakes = FuturesUnordered::new();
+		let mut buf = vec![0u8; self.max_identity_size];
+		loop {
+			let accepting = handshakes.len() < self.max_handshakes.max(1);
+			select! {
+				x = udp_socket.recv_from(&mut buf), if accepting => {
+					let (len, addr) = x?;
+					// identities that don't fit are truncated and fail to parse
+					let Ok(packet) = json::from_slice::<Packet>(&buf[..len]) else {
+						debug!("ignoring invalid identity from {:?}", addr);
+						continue;
+					};
+					if let Ok(identity) = json::from_value::<Identity>(packet.body)
+						&& identity.device_id != self.device_id
+						&& let Some(tcp_port) = identity.tcp_port
+					{
+						let addr = SocketAddr::new(addr.ip(), tcp_port);
+						handshakes.push(self.connect_to_device(identity, addr, settings));
+					}
 				}
+				Some(()) = handshakes.next() => {}
 			}
 		}
 	}
 
-	async fn send_identity_once(&self) -> Result<()> {
-		self.udp_socket
+	async fn connect_to_device(
+		&self,
+		identity: Identity,
+		addr: SocketAddr,
+		settings: &LinkSettings,
+	) {
+		if !self.claim_client(&identity.device_id).await {
+			debug!("ignoring reconnect to client {:?}", identity.device_id);
+			return;
+		}
+
+		let dev_id = identity.device_id.clone();
+
+		let ret = async {
+			let stream = self.handshake(TcpStream::connect(addr)).await?;
+			self.set_link_keepalive(&stream);
+			let mut stream = BufReader::new(stream);
+			let own_identity = json::to_string(&self.make_identity(None).await)? + "\n";
+			self.handshake(stream.write_all(own_identity.as_bytes()))
+				.await?;
+
+			let server_config = self
+				.tls_identity
+				.server_config(self.get_paired_cert(&identity.device_id).await)?;
+
+			let stream = self
+				.handshake(TlsAcceptor::from(server_config).accept(stream))
+				.await?;
+
+			let mut stream = stream.into();
+			let (identity, protocol_version) =
+				self.exchange_identity(&mut stream, identity).await?;
+			info!("new device discovered through udp: {:#?}", identity);
+
+			self.add_device(identity, protocol_version, stream, settings)
+				.await
+		}
+		.await;
+		if let Err(err) = ret {
+			error!(
+				"error while connecting to device discovered through udp: {:?}",
+				err
+			);
+			self.connected_clients.lock().await.retain(|x| *x != dev_id);
+		}
+	}
+
+	// the other device connects back over tcp when it recieves our identity
+	async fn send_identity_to(&self, addr: SocketAddr) -> Result<()> {
+		let udp_socket = match addr {
+			SocketAddr::V4(_) => &self.udp_socket,
+			SocketAddr::V6(_) => self
+				.udp_socket_v6
+				.as_ref()
+				.ok_or(io::Error::from(io::ErrorKind::AddrNotAvailable))?,
+		};
+		udp_socket
 			.send_to(
-				&json::to_vec(&self.make_identity(Some(KDECONNECT_PORT)))?,
-				SocketAddrV4::new(Ipv4Addr::BROADCAST, KDECONNECT_PORT),
+				&json::to_vec(&self.make_identity(Some(KDECONNECT_PORT)).await)?,
+				addr,
 			)
 			.await?;
+		Ok(())
+	}
+
+	// many systems only send the limited broadcast out of one interface, so every subnet gets a
+	// directed broadcast
+	async fn send_identity_once(&self) -> Result<()> {
+		let mut targets: Vec<SocketAddr> = Vec::new();
+		for interface in self.get_interfaces() {
+			let addr: SocketAddr = match (interface.addr, interface.index) {
+				(
+					IfAddr::V4(Ifv4Addr {
+						broadcast: Some(broadcast),
+						..
+					}),
+					_,
+				) => SocketAddrV4::new(broadcast, KDECONNECT_PORT).into(),
+				(IfAddr::V6(_), Some(index)) if self.udp_socket_v6.is_some() => {
+					SocketAddrV6::new(IPV6_ALL_NODES, KDECONNECT_PORT, 0, index).into()
+				}
+				_ => continue,
+			};
+			if !targets.contains(&addr) {
+				targets.push(addr);
+			}
+		}
+		// interfaces can't be listed everywhere
+		if targets.is_empty() && !self.has_interface_filter() {
+			targets.push(SocketAddrV4::new(Ipv4Addr::BROADCAST, KDECONNECT_PORT).into());
+		}
+
+		let mut ret = Err(KdeConnectError::NoUsableInterfaces);
+		for addr in targets {
+			// reaching any interface is enough
+			ret = ret.or(self.send_identity_to(addr).await);
+		}
+		ret?;
 		debug!("broadcasted identity over udp");
 		Ok(())
 	}
 
+	async fn add_custom_device(&self, addr: SocketAddr) -> Result<()> {
+		let mut custom_devices = self.custom_devices.lock().await;
+		if !custom_devices.contains(&addr) {
+			custom_devices.push(addr);
+			self.config.store_custom_devices(&custom_devices).await?;
+		}
+		drop(custom_devices);
+		self.send_identity_to(addr).await
+	}
+
+	async fn remove_custom_device(&self, addr: SocketAddr) -> Result<()> {
+		let mut custom_devices = self.custom_devices.lock().await;
+		custom_devices.retain(|x| *x != addr);
+		self.config.store_custom_devices(&custom_devices).await
+	}
+
+	async fn dial_custom_devices(&self) {
+		let custom_devices = self.custom_devices.lock().await.clone();
+		for addr in custom_devices {
+			if let Err(err) = self.send_identity_to(addr).await {
+				debug!(
+					"failed to send identity to custom device {:?}: {:?}",
+					addr, err
+				);
+			}
+		}
+	}
+
+	// paired devices are nudged with our identity until they connect again
+	async fn reconnect_devices(&self) -> Result<()> {
+		let mut disconnected_rx = self.disconnected_rx.lock().await;
+		let mut pending: HashMap<String, PendingReconnect> = HashMap::new();
+		let queue = |pending: &mut HashMap<String, PendingReconnect>, config: DeviceConfig| {
+			if let Some(ip) = config.last_address {
+				pending.insert(config.id, PendingReconnect::new(ip));
+			}
+		};
+
+		// devices from the last time we ran
+		let configs = self
+			.config
+			.retrieve_all_device_configs()
+			.await
+			.unwrap_or_else(|err| {
+				error!("failed to retrieve device configs: {:?}", err);
+				Vec::new()
+			});
+		for config in configs {
+			if config.is_paired() {
+				queue(&mut pending, config);
+			}
+		}
+
+		let mut network_interval = interval(NETWORK_POLL_INTERVAL);
+		network_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+		let mut addrs = self.get_interface_addrs();
+
+		loop {
+			let next_attempt = pending.values().map(|x| x.next_attempt).min();
+			let attempt = sleep_until(next_attempt.unwrap_or_else(Instant::now));
+			select! {
+				Some(config) = disconnected_rx.recv() => queue(&mut pending, config),
+				_ = attempt, if next_attempt.is_some() => self.try_reconnect(&mut pending).await,
+				_ = network_interval.tick() => {
+					let new_addrs = self.get_interface_addrs();
+					if new_addrs != addrs {
+						addrs = new_addrs;
+						info!("network changed, broadcasting identity");
+						self.handle_network_change().await;
+						// devices might be reachable now
+						for reconnect in pending.values_mut() {
+							*reconnect = PendingReconnect::new(reconnect.ip);
+						}
+					}
+				}
+			}
+		}
+	}
+
+	async fn handle_network_change(&self) {
+		if let Err(err) = self.send_identity_once().await {
+			error!("failed to broadcast identity: {:?}", err);
+		}
+		self.dial_custom_devices().await;
+		// advertise the new addresses
+		if let Err(err) = self.publish_mdns().await {
+			error!("failed to publish mdns service: {:?}", err);
+		}
+	}
+
+	async fn try_reconnect(&self, pending: &mut HashMap<String, PendingReconnect>) {
+		let connected = self.connected_clients.lock().await.clone();
+		pending.retain(|id, _| !connected.contains(id));
+		let now = Instant::now();
+		for (id, reconnect) in pending.iter_mut().filter(|(_, x)| x.next_attempt <= now) {
+			debug!("trying to reconnect to {:?} at {:?}", id, reconnect.ip);
+			let addr = SocketAddr::new(reconnect.ip, KDECONNECT_PORT);
+			if let Err(err) = self.send_identity_to(addr).await {
+				debug!("failed to send identity to {:?}: {:?}", id, err);
+			}
+			reconnect.backoff = (reconnect.backoff * 2).min(self.reconnect_max_backoff);
+			reconnect.next_attempt = now + reconnect.backoff;
+		}
+	}
+
 	async fn send_on_udp(&self) -> Result<()> {
 		info!("broadcasting on udp");
 		// wait until everything else is ready
@@ -396,7 +931,11 @@ impl KdeConnect {
 		let mut interval = interval(Duration::from_secs(30));
 		interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 		loop {
-			self.send_identity_once().await?;
+			// the network might come back
+			if let Err(err) = self.send_identity_once().await {
+				error!("failed to broadcast identity: {:?}", err);
+			}
+			self.dial_custom_devices().await;
 			interval.tick().await;
 		}
 	}
@@ -404,55 +943,61 @@ impl KdeConnect {
 	async fn publish_mdns(&self) -> Result<String> {
 		let mut props = HashMap::new();
 		props.insert("id".to_string(), self.device_id.clone());
-		props.insert("name".to_string(), self.device_name.clone());
+		props.insert("name".to_string(), self.device_name().await);
 		props.insert("type".to_string(), self.device_type.to_string());
 		props.insert("protocol".to_string(), PROTOCOL_VERSION.to_string());
+		let mut addrs: Vec<IpAddr> = self.get_interfaces().iter().map(|x| x.ip()).collect();
 		// local_ip_addr correctly pulls in the ip address for ios
+		if addrs.is_empty()
+			&& !self.has_interface_filter()
+			&& let Ok(addr) = local_ip_addr::get_local_ip_address()
+			&& let Ok(addr) = addr.parse()
+		{
+			addrs.push(addr);
+		}
 		let conf = ServiceInfo::new(
 			"_kdeconnect._udp.local.",
 			&self.device_id,
 			&self.device_id,
-			local_ip_addr::get_local_ip_address()
-				.map_or(vec![], |x| vec![x])
-				.as_slice(),
+			addrs.as_slice(),
 			KDECONNECT_PORT,
 			props,
-		)?
-		.enable_addr_auto();
+		)?;
 		let fullname = conf.get_fullname().to_string();
 		self.mdns.register(conf)?;
 		Ok(fullname)
 	}
 
+	fn select_mdns_interfaces(&self) -> Result<()> {
+		if !self.allowed_interfaces.is_empty() {
+			self.mdns.disable_interface(IfKind::All)?;
+			self.mdns
+				.enable_interface(self.allowed_interfaces.iter().collect::<Vec<_>>())?;
+		}
+		if !self.denied_interfaces.is_empty() {
+			self.mdns
+				.disable_interface(self.denied_interfaces.iter().collect::<Vec<_>>())?;
+		}
+		Ok(())
+	}
+
 	async fn discover_mdns(&self) -> Result<()> {
 		let browser = self.mdns.browse("_kdeconnect._udp.local.")?;
 		while let Ok(service) = browser.recv_async().await {
 			if let ServiceEvent::ServiceResolved(info) = service
 				&& let Some(id) = info.get_property_val_str("id")
 				&& id != self.device_id
-				&& let Some(addr) = info.get_addresses().iter().next()
 			{
 				info!(
 					"new device discovered through mdns, sending identity: {:?}",
 					info.get_fullname()
 				);
-				let addr = SocketAddr::new(*addr, info.get_port());
-				let bind_addr = if addr.is_ipv4() {
-					SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
-				} else {
-					SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
-				};
-
-				let ret = async {
-					UdpSocket::bind(bind_addr)
-						.await?
-						.send_to(
-							&json::to_vec(&self.make_identity(Some(KDECONNECT_PORT)))?,
-							addr,
-						)
-						.await
+				// the device may have addresses of both families, any one is enough
+				let mut ret = Err(KdeConnectError::Other);
+				for addr in info.get_addresses() {
+					let addr = SocketAddr::new(*addr, info.get_port());
+					ret = ret.or(self.send_identity_to(addr).await);
 				}
-				.await;
 				if let Err(err) = ret {
 					error!("error while sending identity to mdns device: {:?}", err);
 				} else {
@@ -462,6 +1007,73 @@ impl KdeConnect {
 		}
 		Ok(())
 	}
+
+	fn set_link_keepalive(&self, stream: &TcpStream) {
+		if let Some(keepalive) = self.link_keepalive.as_ref()
+			&& let Err(err) = util::set_keepalive(stream, keepalive)
+		{
+			warn!("failed to set keepalive: {:?}", err);
+		}
+	}
+
+	fn has_interface_filter(&self) -> bool {
+		!self.allowed_interfaces.is_empty() || !self.denied_interfaces.is_empty()
+	}
+
+	// usable interfaces after applying the allow and deny lists
+	fn get_interfaces(&self) -> Vec<Interface> {
+		if_addrs::get_if_addrs()
+			.unwrap_or_default()
+			.into_iter()
+			.filter(|x| {
+				!x.is_loopback()
+					&& (self.allowed_interfaces.is_empty()
+						|| self.allowed_interfaces.contains(&x.name))
+					&& !self.denied_interfaces.contains(&x.name)
+			})
+			.collect()
+	}
+
+	fn get_interface_addrs(&self) -> Vec<IpAddr> {
+		let mut addrs: Vec<IpAddr> = self.get_interfaces().iter().map(|x| x.ip()).collect();
+		addrs.sort();
+		addrs
+	}
+}
+
+// defaults are the ones kdeconnect-kde uses, a dead link is noticed after
+// idle + interval * retries
+#[derive(Clone, Debug)]
+pub struct LinkKeepalive {
+	pub idle: Duration,
+	pub interval: Duration,
+	pub retries: u32,
+}
+
+impl Default for LinkKeepalive {
+	fn default() -> Self {
+		Self {
+			idle: Duration::from_secs(10),
+			interval: Duration::from_secs(5),
+			retries: 3,
+		}
+	}
+}
+
+struct PendingReconnect {
+	ip: IpAddr,
+	backoff: Duration,
+	next_attempt: Instant,
+}
+
+impl PendingReconnect {
+	fn new(ip: IpAddr) -> Self {
+		Self {
+			ip,
+			backoff: RECONNECT_MIN_BACKOFF,
+			next_attempt: Instant::now(),
+		}
+	}
 }
 
 pub struct KdeConnectClient {
@@ -475,4 +1087,48 @@ impl KdeConnectClient {
 			.send(KdeConnectAction::BroadcastIdentity(tx))?;
 		rx.await?
 	}
+
+	// sends our identity to the address so the device connects to us, like broadcasting but
+	// for networks where broadcasts don't get through
+	pub async fn connect_to(&self, addr: SocketAddr) -> Result<()> {
+		let (tx, rx) = oneshot::channel();
+		self.client_tx.send(KdeConnectAction::ConnectTo(addr, tx))?;
+		rx.await?
+	}
+
+	// custom devices are stored and dialed along with every broadcast
+	pub async fn add_custom_device(&self, addr: SocketAddr) -> Result<()> {
+		let (tx, rx) = oneshot::channel();
+		self.client_tx
+			.send(KdeConnectAction::AddCustomDevice(addr, tx))?;
+		rx.await?
+	}
+
+	pub async fn remove_custom_device(&self, addr: SocketAddr) -> Result<()> {
+		let (tx, rx) = oneshot::channel();
+		self.client_tx
+			.send(KdeConnectAction::RemoveCustomDevice(addr, tx))?;
+		rx.await?
+	}
+
+	// devices that are already connected only see the new name once they reconnect
+	pub async fn rename(&self, device_name: String) -> Result<()> {
+		let (tx, rx) = oneshot::channel();
+		self.client_tx
+			.send(KdeConnectAction::Rename(device_name, tx))?;
+		rx.await?
+	}
+
+	// stops the server and closes all device links, KdeConnect::start_server returns afterwards
+	pub async fn shutdown(&self) -> Result<()> {
+		let (tx, rx) = oneshot::channel();
+		self.client_tx.send(KdeConnectAction::Shutdown(tx))?;
+		rx.await?
+	}
+
+	pub async fn custom_devices(&self) -> Result<Vec<SocketAddr>> {
+		let (tx, rx) = oneshot::channel();
+		self.client_tx.send(KdeConnectAction::CustomDevices(tx))?;
+		Ok(rx.await?)
+	}
 }
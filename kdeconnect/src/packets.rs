@@ -1,9 +1,9 @@
 use std::{collections::HashMap, fmt::Display, str::FromStr};
 
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 
-pub const PROTOCOL_VERSION: usize = 7;
+pub const PROTOCOL_VERSION: usize = 8;
 
 pub const ALL_CAPABILITIES: &[&str] = &[
 	Ping::TYPE,
@@ -25,6 +25,24 @@ pub const ALL_CAPABILITIES: &[&str] = &[
 	MousepadKeyboardState::TYPE,
 	RunCommand::TYPE,
 	RunCommandRequest::TYPE,
+	Notification::TYPE,
+	NotificationRequest::TYPE,
+	NotificationAction::TYPE,
+	NotificationReply::TYPE,
+	SmsRequestConversations::TYPE,
+	SmsRequestConversation::TYPE,
+	SmsMessages::TYPE,
+	SmsRequestAttachment::TYPE,
+	SmsAttachmentFile::TYPE,
+	SmsRequest::TYPE,
+	ContactsRequestAllUidsTimestamps::TYPE,
+	ContactsRequestVcardsByUid::TYPE,
+	ContactsResponseUidsTimestamps::TYPE,
+	ContactsResponseVcards::TYPE,
+	Lock::TYPE,
+	LockRequest::TYPE,
+	Sftp::TYPE,
+	SftpRequest::TYPE,
 ];
 
 macro_rules! derive_type {
@@ -121,6 +139,19 @@ pub struct Packet {
 	pub payload_transfer_info: Option<PacketPayloadTransferInfo>,
 }
 
+impl Packet {
+	// for packet types this crate doesn't know about
+	pub fn new(packet_type: impl Into<String>, body: Value) -> Self {
+		Self {
+			id: crate::util::get_time_ms(),
+			packet_type: packet_type.into(),
+			body,
+			payload_size: None,
+			payload_transfer_info: None,
+		}
+	}
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PacketPayloadTransferInfo {
 	pub port: u16,
@@ -142,6 +173,9 @@ derive_type!(Identity, "kdeconnect.identity");
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct Pair {
 	pub pair: bool,
+	// protocol v8, seconds since unix epoch, only sent on pair requests
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub timestamp: Option<u64>,
 }
 derive_type!(Pair, "kdeconnect.pair");
 
@@ -594,6 +628,299 @@ derive_type!(Telephony, "kdeconnect.telephony");
 pub struct TelephonyRequestMute {}
 derive_type!(TelephonyRequestMute, "kdeconnect.telephony.request_mute");
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+	pub id: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub is_cancel: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub is_clearable: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub app_name: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub ticker: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub title: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub text: Option<String>,
+	// milliseconds since unix epoch as a string
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub time: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub silent: Option<bool>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub only_once: Option<bool>,
+	// set if the notification supports inline replies
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub request_reply_id: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub actions: Option<Vec<String>>,
+	// md5 of the icon payload, used by kdeconnect-kde to cache icons
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub payload_hash: Option<String>,
+}
+derive_type!(Notification, "kdeconnect.notification");
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum NotificationRequest {
+	Request { request: bool },
+	Dismiss { cancel: String },
+}
+derive_type!(NotificationRequest, "kdeconnect.notification.request");
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationAction {
+	pub key: String,
+	pub action: String,
+}
+derive_type!(NotificationAction, "kdeconnect.notification.action");
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationReply {
+	pub request_reply_id: String,
+	pub message: String,
+}
+derive_type!(NotificationReply, "kdeconnect.notification.reply");
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct SmsRequestConversations {}
+derive_type!(
+	SmsRequestConversations,
+	"kdeconnect.sms.request_conversations"
+);
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SmsRequestConversation {
+	#[serde(rename = "threadID")]
+	pub thread_id: i64,
+	// only messages older than this are sent, used for paging
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub range_start_timestamp: Option<i64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub number_to_request: Option<i64>,
+}
+derive_type!(
+	SmsRequestConversation,
+	"kdeconnect.sms.request_conversation"
+);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmsMessages {
+	pub messages: Vec<SmsMessage>,
+	pub version: Option<i32>,
+}
+derive_type!(SmsMessages, "kdeconnect.sms.messages");
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmsMessage {
+	// bitfield: 1 is a text message, 2 is a multi target message
+	pub event: i32,
+	pub body: String,
+	pub addresses: Vec<SmsAddress>,
+	// milliseconds since unix epoch
+	pub date: i64,
+	// android's Telephony.TextBasedSmsColumns type: 1 is inbox, 2 is sent
+	#[serde(rename = "type")]
+	pub message_type: i32,
+	pub read: i32,
+	pub thread_id: i64,
+	#[serde(rename = "_id")]
+	pub uid: i64,
+	pub sub_id: Option<i64>,
+	pub attachments: Option<Vec<SmsAttachment>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmsAddress {
+	pub address: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmsAttachment {
+	pub part_id: i64,
+	pub mime_type: String,
+	// base64 encoded
+	pub encoded_thumbnail: Option<String>,
+	pub unique_identifier: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmsRequestAttachment {
+	pub part_id: i64,
+	pub unique_identifier: String,
+}
+derive_type!(SmsRequestAttachment, "kdeconnect.sms.request_attachment");
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmsAttachmentFile {
+	// the unique_identifier of the requested attachment
+	pub filename: String,
+}
+derive_type!(SmsAttachmentFile, "kdeconnect.sms.attachment_file");
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SmsRequest {
+	pub version: i32,
+	pub addresses: Vec<SmsAddress>,
+	pub message_body: String,
+	#[serde(rename = "subID", skip_serializing_if = "Option::is_none")]
+	pub sub_id: Option<i64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub attachments: Option<Vec<SmsOutgoingAttachment>>,
+}
+derive_type!(SmsRequest, "kdeconnect.sms.request");
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SmsOutgoingAttachment {
+	pub file_name: String,
+	pub base64_encoded_file: String,
+	pub mime_type: String,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct ContactsRequestAllUidsTimestamps {}
+derive_type!(
+	ContactsRequestAllUidsTimestamps,
+	"kdeconnect.contacts.request_all_uids_timestamps"
+);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ContactsRequestVcardsByUid {
+	pub uids: Vec<String>,
+}
+derive_type!(
+	ContactsRequestVcardsByUid,
+	"kdeconnect.contacts.request_vcards_by_uid"
+);
+
+// every uid is also a key in the body, only those keys are read so other fields can't break it
+struct ByUid<T> {
+	uids: Vec<String>,
+	values: HashMap<String, T>,
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for ByUid<T> {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		let mut body = HashMap::<String, Value>::deserialize(deserializer)?;
+		let uids = body
+			.remove("uids")
+			.ok_or_else(|| serde::de::Error::missing_field("uids"))?;
+		let uids: Vec<String> = serde_json::from_value(uids).map_err(serde::de::Error::custom)?;
+		let mut values = HashMap::with_capacity(uids.len());
+		for uid in &uids {
+			if let Some(value) = body.remove(uid) {
+				let value = serde_json::from_value(value).map_err(serde::de::Error::custom)?;
+				values.insert(uid.clone(), value);
+			}
+		}
+		Ok(Self { uids, values })
+	}
+}
+
+// every uid is also a key in the body, with the last modified timestamp as the value
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(from = "ByUid<i64>")]
+pub struct ContactsResponseUidsTimestamps {
+	pub uids: Vec<String>,
+	#[serde(flatten)]
+	pub timestamps: HashMap<String, i64>,
+}
+derive_type!(
+	ContactsResponseUidsTimestamps,
+	"kdeconnect.contacts.response_uids_timestamps"
+);
+
+impl From<ByUid<i64>> for ContactsResponseUidsTimestamps {
+	fn from(x: ByUid<i64>) -> Self {
+		Self {
+			uids: x.uids,
+			timestamps: x.values,
+		}
+	}
+}
+
+// every uid is also a key in the body, with the vcard as the value
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(from = "ByUid<String>")]
+pub struct ContactsResponseVcards {
+	pub uids: Vec<String>,
+	#[serde(flatten)]
+	pub vcards: HashMap<String, String>,
+}
+derive_type!(
+	ContactsResponseVcards,
+	"kdeconnect.contacts.response_vcards"
+);
+
+impl From<ByUid<String>> for ContactsResponseVcards {
+	fn from(x: ByUid<String>) -> Self {
+		Self {
+			uids: x.uids,
+			vcards: x.values,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Lock {
+	pub is_locked: bool,
+}
+derive_type!(Lock, "kdeconnect.lock");
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[serde(untagged)]
+pub enum LockRequest {
+	Request {
+		#[serde(rename = "requestLocked")]
+		request_locked: bool,
+	},
+	Set {
+		#[serde(rename = "setLocked")]
+		set_locked: bool,
+	},
+}
+derive_type!(LockRequest, "kdeconnect.lock.request");
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Sftp {
+	Info(SftpInfo),
+	Error {
+		#[serde(rename = "errorMessage")]
+		error_message: String,
+	},
+}
+derive_type!(Sftp, "kdeconnect.sftp");
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SftpInfo {
+	pub ip: String,
+	pub port: u16,
+	pub user: String,
+	pub password: String,
+	pub path: String,
+	pub multi_paths: Option<Vec<String>>,
+	pub path_names: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SftpRequest {
+	pub start_browsing: bool,
+}
+derive_type!(SftpRequest, "kdeconnect.sftp.request");
+
 // to_value should never fail, as Serialize will always be successful and packets should never
 // contain non-string keys anyway
 #[macro_export]
@@ -638,3 +965,28 @@ macro_rules! make_packet_str_payload {
 			.map(|x| x + "\n")
 	};
 }
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	#[test]
+	fn contacts_responses_ignore_other_fields() {
+		let body = json!({ "uids": ["1", "2"], "1": 10, "2": 20, "extra": "not a timestamp" });
+		let response: ContactsResponseUidsTimestamps = serde_json::from_value(body).unwrap();
+		assert_eq!(response.uids, ["1", "2"]);
+		assert_eq!(
+			response.timestamps,
+			HashMap::from([("1".into(), 10), ("2".into(), 20)])
+		);
+
+		let body = json!({ "uids": ["1"], "1": "BEGIN:VCARD", "extra": 1 });
+		let response: ContactsResponseVcards = serde_json::from_value(body).unwrap();
+		assert_eq!(
+			response.vcards,
+			HashMap::from([("1".into(), "BEGIN:VCARD".into())])
+		);
+	}
+}
@@ -0,0 +1,362 @@
+pub mod battery;
+pub mod clipboard;
+pub mod connectivity_report;
+pub mod contacts;
+pub mod findmyphone;
+pub mod lock;
+pub mod mousepad;
+pub mod mpris;
+pub mod notification;
+pub mod ping;
+pub mod presenter;
+pub mod runcommand;
+pub mod sftp;
+pub mod share;
+pub mod sms;
+pub mod systemvolume;
+pub mod telephony;
+
+use std::{
+	collections::HashMap,
+	future::Future,
+	net::{IpAddr, SocketAddr},
+	pin::Pin,
+	sync::Arc,
+};
+
+use log::{debug, warn};
+use serde_json as json;
+use tokio::{io::AsyncRead, time::Instant};
+use tokio_rustls::rustls::{ClientConfig, ServerConfig};
+
+use crate::{
+	device::{DeviceCapabilities, DeviceConfig, LockedDeviceWrite},
+	packets::{Packet, PacketPayloadTransferInfo},
+	transfer::{PayloadConfig, TransferHandle},
+	util::{create_payload, get_payload},
+	KdeConnectError, Result,
+};
+
+// a capability of a device, every device gets its own instance of each plugin
+#[async_trait::async_trait]
+pub trait Plugin {
+	// only called with packet types the plugin was registered to recieve
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()>;
+	// called when a paired device connects, finishes pairing or pings
+	async fn send_state(&mut self, _device: &PluginDevice<'_>) -> Result<()> {
+		Ok(())
+	}
+	async fn handle_pair_status_change(&mut self, _paired: bool) {}
+	// handle_timeout is called once this passes, for state the device may have abandoned
+	fn timeout(&self) -> Option<Instant> {
+		None
+	}
+	async fn handle_timeout(&mut self) {}
+	async fn handle_exit(&mut self) {}
+}
+
+// default capabilities of a plugin
+pub trait PluginInfo {
+	const INCOMING_CAPABILITIES: &'static [&'static str];
+	const OUTGOING_CAPABILITIES: &'static [&'static str];
+}
+
+// the device a plugin is running for
+pub struct PluginDevice<'a> {
+	pub(crate) config: &'a DeviceConfig,
+	pub(crate) capabilities: &'a Arc<DeviceCapabilities>,
+	pub(crate) stream_w: &'a LockedDeviceWrite,
+	pub(crate) addr: SocketAddr,
+	pub(crate) local_ip: IpAddr,
+	pub(crate) client_config: &'a Arc<ClientConfig>,
+	pub(crate) server_config: &'a Arc<ServerConfig>,
+	pub(crate) payload_config: &'a PayloadConfig,
+}
+
+impl PluginDevice<'_> {
+	pub fn config(&self) -> &DeviceConfig {
+		self.config
+	}
+
+	pub fn is_paired(&self) -> bool {
+		self.config.is_paired()
+	}
+
+	pub fn capabilities(&self) -> &DeviceCapabilities {
+		self.capabilities
+	}
+
+	// address the device reached us on
+	pub fn local_ip(&self) -> IpAddr {
+		self.local_ip
+	}
+
+	// for sending packets after handle_packet returns
+	pub fn sender(&self) -> PacketSender {
+		PacketSender {
+			stream_w: self.stream_w.clone(),
+			capabilities: self.capabilities.clone(),
+		}
+	}
+
+	// fails with KdeConnectError::UnsupportedPacketType if the device didn't declare the packet
+	pub async fn send_packet(&self, packet: &Packet) -> Result<()> {
+		send_packet(self.stream_w, self.capabilities, packet).await
+	}
+
+	// size is the payload size of the packet, reads fail if the payload doesn't match it
+	pub async fn get_payload(
+		&self,
+		transfer_info: PacketPayloadTransferInfo,
+		size: Option<i64>,
+	) -> Result<Pin<Box<dyn AsyncRead + Sync + Send>>> {
+		let transfer = TransferHandle::new(size.unwrap_or(-1));
+		self.get_payload_tracked(transfer_info, transfer).await
+	}
+
+	// reads from the payload fail once transfer is cancelled or the device stops sending
+	pub async fn get_payload_tracked(
+		&self,
+		transfer_info: PacketPayloadTransferInfo,
+		transfer: TransferHandle,
+	) -> Result<Pin<Box<dyn AsyncRead + Sync + Send>>> {
+		get_payload(
+			self.addr,
+			transfer_info,
+			self.client_config.clone(),
+			self.payload_config.clone(),
+			transfer,
+		)
+		.await
+	}
+
+	// the returned future sends the payload once the device connects
+	pub async fn create_payload(
+		&self,
+		payload: impl AsyncRead + Sync + Send + Unpin,
+	) -> Result<(u16, impl Future<Output = Result<()>> + Sync + Send)> {
+		create_payload(
+			payload,
+			self.local_ip,
+			self.server_config.clone(),
+			self.payload_config.clone(),
+			TransferHandle::new(-1),
+		)
+		.await
+	}
+}
+
+#[derive(Clone)]
+pub struct PacketSender {
+	stream_w: LockedDeviceWrite,
+	capabilities: Arc<DeviceCapabilities>,
+}
+
+impl PacketSender {
+	pub async fn send_packet(&self, packet: &Packet) -> Result<()> {
+		send_packet(&self.stream_w, &self.capabilities, packet).await
+	}
+}
+
+async fn send_packet(
+	stream_w: &LockedDeviceWrite,
+	capabilities: &DeviceCapabilities,
+	packet: &Packet,
+) -> Result<()> {
+	if !capabilities.can_send(&packet.packet_type) {
+		return Err(KdeConnectError::UnsupportedPacketType(
+			packet.packet_type.clone(),
+		));
+	}
+	Ok(stream_w.send(json::to_string(packet)? + "\n").await?)
+}
+
+// plugins send their state whether or not the device wants it
+fn ignore_unsupported(ret: Result<()>) -> Result<()> {
+	match ret {
+		Err(KdeConnectError::UnsupportedPacketType(packet_type)) => {
+			debug!("not sending undeclared packet type {:?}", packet_type);
+			Ok(())
+		}
+		ret => ret,
+	}
+}
+
+type PluginConstructor<C> = Box<dyn Fn(&C) -> Box<dyn Plugin + Sync + Send> + Sync + Send>;
+
+struct PluginEntry<C> {
+	incoming_capabilities: Vec<String>,
+	outgoing_capabilities: Vec<String>,
+	create: PluginConstructor<C>,
+}
+
+// plugins are created for every device from a context, for example the integrator's handler
+pub struct PluginRegistry<C> {
+	entries: Vec<PluginEntry<C>>,
+	// declared without a plugin, these go to DeviceHandler::handle_unknown_packet
+	passthrough_incoming: Vec<String>,
+	passthrough_outgoing: Vec<String>,
+}
+
+impl<C> Default for PluginRegistry<C> {
+	fn default() -> Self {
+		Self {
+			entries: Vec::new(),
+			passthrough_incoming: Vec::new(),
+			passthrough_outgoing: Vec::new(),
+		}
+	}
+}
+
+impl<C> PluginRegistry<C> {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn register<P: Plugin + PluginInfo + Sync + Send + 'static>(
+		&mut self,
+		create: impl Fn(&C) -> P + Sync + Send + 'static,
+	) -> &mut Self {
+		self.register_with_capabilities(
+			P::INCOMING_CAPABILITIES
+				.iter()
+				.map(|x| x.to_string())
+				.collect(),
+			P::OUTGOING_CAPABILITIES
+				.iter()
+				.map(|x| x.to_string())
+				.collect(),
+			create,
+		)
+	}
+
+	// the plugin only recieves packets listed in incoming_capabilities
+	pub fn register_with_capabilities<P: Plugin + Sync + Send + 'static>(
+		&mut self,
+		incoming_capabilities: Vec<String>,
+		outgoing_capabilities: Vec<String>,
+		create: impl Fn(&C) -> P + Sync + Send + 'static,
+	) -> &mut Self {
+		self.entries.push(PluginEntry {
+			incoming_capabilities,
+			outgoing_capabilities,
+			create: Box::new(move |ctx| Box::new(create(ctx))),
+		});
+		self
+	}
+
+	// declares packet types that no plugin handles, incoming ones are passed to
+	// DeviceHandler::handle_unknown_packet and outgoing ones can be sent with
+	// DeviceClient::send_raw
+	pub fn register_passthrough(
+		&mut self,
+		incoming_capabilities: Vec<String>,
+		outgoing_capabilities: Vec<String>,
+	) -> &mut Self {
+		self.passthrough_incoming.extend(incoming_capabilities);
+		self.passthrough_outgoing.extend(outgoing_capabilities);
+		self
+	}
+
+	pub fn incoming_capabilities(&self) -> Vec<String> {
+		dedup(
+			self.entries
+				.iter()
+				.flat_map(|x| &x.incoming_capabilities)
+				.chain(&self.passthrough_incoming),
+		)
+	}
+
+	pub fn outgoing_capabilities(&self) -> Vec<String> {
+		dedup(
+			self.entries
+				.iter()
+				.flat_map(|x| &x.outgoing_capabilities)
+				.chain(&self.passthrough_outgoing),
+		)
+	}
+
+	pub fn create_plugins(&self, ctx: &C) -> DevicePlugins {
+		let mut plugins = DevicePlugins {
+			plugins: Vec::with_capacity(self.entries.len()),
+			packet_types: HashMap::new(),
+		};
+		for entry in self.entries.iter() {
+			let idx = plugins.plugins.len();
+			plugins.plugins.push((entry.create)(ctx));
+			for packet_type in entry.incoming_capabilities.iter() {
+				if plugins.packet_types.contains_key(packet_type) {
+					warn!("{:?} is handled by multiple plugins, ignoring", packet_type);
+				} else {
+					plugins.packet_types.insert(packet_type.clone(), idx);
+				}
+			}
+		}
+		plugins
+	}
+}
+
+fn dedup<'a>(capabilities: impl Iterator<Item = &'a String>) -> Vec<String> {
+	let mut out: Vec<String> = Vec::new();
+	for capability in capabilities {
+		if !out.contains(capability) {
+			out.push(capability.clone());
+		}
+	}
+	out
+}
+
+// plugin instances of one device
+pub struct DevicePlugins {
+	plugins: Vec<Box<dyn Plugin + Sync + Send>>,
+	packet_types: HashMap<String, usize>,
+}
+
+impl DevicePlugins {
+	// returns the packet if no plugin handles it
+	pub(crate) async fn handle_packet(
+		&mut self,
+		device: &PluginDevice<'_>,
+		packet: Packet,
+	) -> Result<Option<Packet>> {
+		match self.packet_types.get(&packet.packet_type) {
+			Some(idx) => {
+				ignore_unsupported(self.plugins[*idx].handle_packet(device, packet).await)?;
+				Ok(None)
+			}
+			None => Ok(Some(packet)),
+		}
+	}
+
+	pub(crate) async fn send_state(&mut self, device: &PluginDevice<'_>) -> Result<()> {
+		for plugin in self.plugins.iter_mut() {
+			ignore_unsupported(plugin.send_state(device).await)?;
+		}
+		Ok(())
+	}
+
+	pub(crate) async fn handle_pair_status_change(&mut self, paired: bool) {
+		for plugin in self.plugins.iter_mut() {
+			plugin.handle_pair_status_change(paired).await;
+		}
+	}
+
+	pub(crate) fn next_timeout(&self) -> Option<Instant> {
+		self.plugins.iter().filter_map(|x| x.timeout()).min()
+	}
+
+	pub(crate) async fn handle_timeouts(&mut self) {
+		let now = Instant::now();
+		for plugin in self.plugins.iter_mut() {
+			if plugin.timeout().is_some_and(|x| x <= now) {
+				plugin.handle_timeout().await;
+			}
+		}
+	}
+
+	pub(crate) async fn handle_exit(&mut self) {
+		for plugin in self.plugins.iter_mut() {
+			plugin.handle_exit().await;
+		}
+	}
+}
@@ -0,0 +1,49 @@
+use serde_json as json;
+
+use crate::{
+	make_packet,
+	packets::{Battery, BatteryRequest, Packet, PacketType},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait BatteryHandler {
+	async fn handle_battery(&mut self, packet: Battery);
+	async fn get_battery(&mut self) -> Battery;
+}
+
+pub struct BatteryPlugin<H> {
+	handler: H,
+}
+
+impl<H> BatteryPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for BatteryPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[Battery::TYPE, BatteryRequest::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[Battery::TYPE, BatteryRequest::TYPE];
+}
+
+#[async_trait::async_trait]
+impl<H: BatteryHandler + Sync + Send> Plugin for BatteryPlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		match packet.packet_type.as_str() {
+			Battery::TYPE => {
+				self.handler
+					.handle_battery(json::from_value(packet.body)?)
+					.await;
+				Ok(())
+			}
+			_ => self.send_state(device).await,
+		}
+	}
+
+	async fn send_state(&mut self, device: &PluginDevice<'_>) -> Result<()> {
+		let battery = self.handler.get_battery().await;
+		device.send_packet(&make_packet!(battery)).await
+	}
+}
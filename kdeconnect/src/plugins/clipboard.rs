@@ -0,0 +1,61 @@
+use serde_json as json;
+
+use crate::{
+	make_packet,
+	packets::{Clipboard, ClipboardConnect, Packet, PacketType},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	util::get_time_ms,
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait ClipboardHandler {
+	async fn handle_clipboard_content(&mut self, content: String);
+	async fn get_clipboard_content(&mut self) -> String;
+}
+
+pub struct ClipboardPlugin<H> {
+	handler: H,
+}
+
+impl<H> ClipboardPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for ClipboardPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] =
+		&[Clipboard::TYPE, ClipboardConnect::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] =
+		&[Clipboard::TYPE, ClipboardConnect::TYPE];
+}
+
+#[async_trait::async_trait]
+impl<H: ClipboardHandler + Sync + Send> Plugin for ClipboardPlugin<H> {
+	async fn handle_packet(&mut self, _device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		match packet.packet_type.as_str() {
+			Clipboard::TYPE => {
+				let clipboard: Clipboard = json::from_value(packet.body)?;
+				self.handler
+					.handle_clipboard_content(clipboard.content)
+					.await;
+			}
+			_ => {
+				let connect: ClipboardConnect = json::from_value(packet.body)?;
+				if connect.timestamp != 0 {
+					self.handler.handle_clipboard_content(connect.content).await;
+				}
+			}
+		}
+		Ok(())
+	}
+
+	async fn send_state(&mut self, device: &PluginDevice<'_>) -> Result<()> {
+		let clipboard = ClipboardConnect {
+			content: self.handler.get_clipboard_content().await,
+			timestamp: get_time_ms(),
+		};
+		device.send_packet(&make_packet!(clipboard)).await
+	}
+}
@@ -0,0 +1,51 @@
+use serde_json as json;
+
+use crate::{
+	make_packet,
+	packets::{ConnectivityReport, ConnectivityReportRequest, Packet, PacketType},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait ConnectivityReportHandler {
+	async fn handle_connectivity_report(&mut self, packet: ConnectivityReport);
+	async fn get_connectivity_report(&mut self) -> ConnectivityReport;
+}
+
+pub struct ConnectivityReportPlugin<H> {
+	handler: H,
+}
+
+impl<H> ConnectivityReportPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for ConnectivityReportPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] =
+		&[ConnectivityReport::TYPE, ConnectivityReportRequest::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] =
+		&[ConnectivityReport::TYPE, ConnectivityReportRequest::TYPE];
+}
+
+#[async_trait::async_trait]
+impl<H: ConnectivityReportHandler + Sync + Send> Plugin for ConnectivityReportPlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		match packet.packet_type.as_str() {
+			ConnectivityReport::TYPE => {
+				self.handler
+					.handle_connectivity_report(json::from_value(packet.body)?)
+					.await;
+				Ok(())
+			}
+			_ => self.send_state(device).await,
+		}
+	}
+
+	async fn send_state(&mut self, device: &PluginDevice<'_>) -> Result<()> {
+		let connectivity = self.handler.get_connectivity_report().await;
+		device.send_packet(&make_packet!(connectivity)).await
+	}
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use log::warn;
+use serde_json as json;
+
+use crate::{
+	make_packet,
+	packets::{
+		ContactsRequestAllUidsTimestamps, ContactsRequestVcardsByUid,
+		ContactsResponseUidsTimestamps, ContactsResponseVcards, Packet, PacketType,
+	},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	vcard::VCard,
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait ContactsHandler {
+	async fn handle_contact_uids_timestamps(&mut self, timestamps: HashMap<String, i64>);
+	async fn handle_contact_vcards(&mut self, vcards: HashMap<String, VCard>);
+	// uid to last modified timestamp
+	async fn get_contact_uids_timestamps(&mut self) -> HashMap<String, i64>;
+	async fn get_contact_vcards(&mut self, uids: Vec<String>) -> HashMap<String, VCard>;
+}
+
+pub struct ContactsPlugin<H> {
+	handler: H,
+}
+
+impl<H> ContactsPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for ContactsPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[
+		ContactsRequestAllUidsTimestamps::TYPE,
+		ContactsRequestVcardsByUid::TYPE,
+		ContactsResponseUidsTimestamps::TYPE,
+		ContactsResponseVcards::TYPE,
+	];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[
+		ContactsRequestAllUidsTimestamps::TYPE,
+		ContactsRequestVcardsByUid::TYPE,
+		ContactsResponseUidsTimestamps::TYPE,
+		ContactsResponseVcards::TYPE,
+	];
+}
+
+#[async_trait::async_trait]
+impl<H: ContactsHandler + Sync + Send> Plugin for ContactsPlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		match packet.packet_type.as_str() {
+			ContactsRequestAllUidsTimestamps::TYPE => {
+				let timestamps = self.handler.get_contact_uids_timestamps().await;
+				let packet = ContactsResponseUidsTimestamps {
+					uids: timestamps.keys().cloned().collect(),
+					timestamps,
+				};
+				device.send_packet(&make_packet!(packet)).await?;
+			}
+			ContactsRequestVcardsByUid::TYPE => {
+				let request: ContactsRequestVcardsByUid = json::from_value(packet.body)?;
+				let vcards = self.handler.get_contact_vcards(request.uids).await;
+				let packet = ContactsResponseVcards {
+					uids: vcards.keys().cloned().collect(),
+					vcards: vcards
+						.into_iter()
+						.map(|(uid, vcard)| (uid, vcard.to_string()))
+						.collect(),
+				};
+				device.send_packet(&make_packet!(packet)).await?;
+			}
+			ContactsResponseUidsTimestamps::TYPE => {
+				let response: ContactsResponseUidsTimestamps = json::from_value(packet.body)?;
+				self.handler
+					.handle_contact_uids_timestamps(response.timestamps)
+					.await;
+			}
+			_ => {
+				let response: ContactsResponseVcards = json::from_value(packet.body)?;
+				let mut vcards = HashMap::with_capacity(response.vcards.len());
+				for (uid, vcard) in response.vcards {
+					match VCard::parse(&vcard) {
+						Ok(vcard) => {
+							vcards.insert(uid, vcard);
+						}
+						Err(err) => {
+							warn!("failed to parse vcard for {:?}: {:?}", uid, err);
+						}
+					}
+				}
+				self.handler.handle_contact_vcards(vcards).await;
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,33 @@
+use crate::{
+	packets::{FindPhone, Packet},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait FindPhoneHandler {
+	async fn handle_find_phone(&mut self);
+}
+
+pub struct FindPhonePlugin<H> {
+	handler: H,
+}
+
+impl<H> FindPhonePlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for FindPhonePlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[FindPhone::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[FindPhone::TYPE];
+}
+
+#[async_trait::async_trait]
+impl<H: FindPhoneHandler + Sync + Send> Plugin for FindPhonePlugin<H> {
+	async fn handle_packet(&mut self, _device: &PluginDevice<'_>, _packet: Packet) -> Result<()> {
+		self.handler.handle_find_phone().await;
+		Ok(())
+	}
+}
@@ -0,0 +1,54 @@
+use serde_json as json;
+
+use crate::{
+	make_packet,
+	packets::{Lock, LockRequest, Packet, PacketType},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait LockHandler {
+	async fn handle_lock_state(&mut self, locked: bool);
+	async fn handle_lock_request(&mut self, locked: bool);
+	async fn get_lock_state(&mut self) -> bool;
+}
+
+pub struct LockPlugin<H> {
+	handler: H,
+}
+
+impl<H> LockPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for LockPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[Lock::TYPE, LockRequest::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[Lock::TYPE, LockRequest::TYPE];
+}
+
+#[async_trait::async_trait]
+impl<H: LockHandler + Sync + Send> Plugin for LockPlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		match packet.packet_type.as_str() {
+			Lock::TYPE => {
+				let lock: Lock = json::from_value(packet.body)?;
+				self.handler.handle_lock_state(lock.is_locked).await;
+				Ok(())
+			}
+			_ => {
+				let request: LockRequest = json::from_value(packet.body)?;
+				if let LockRequest::Set { set_locked } = request {
+					self.handler.handle_lock_request(set_locked).await;
+				}
+				// always report the current state back
+				let packet = Lock {
+					is_locked: self.handler.get_lock_state().await,
+				};
+				device.send_packet(&make_packet!(packet)).await
+			}
+		}
+	}
+}
@@ -0,0 +1,61 @@
+use serde_json as json;
+
+use crate::{
+	packets::{MousepadEcho, MousepadKeyboardState, MousepadRequest, Packet},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait MousepadHandler {
+	async fn handle_mousepad_request(&mut self, action: MousepadRequest);
+	async fn handle_mousepad_keyboard_state(&mut self, state: MousepadKeyboardState);
+	async fn handle_mousepad_echo(&mut self, echo: MousepadEcho);
+}
+
+pub struct MousepadPlugin<H> {
+	handler: H,
+}
+
+impl<H> MousepadPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for MousepadPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[
+		MousepadRequest::TYPE,
+		MousepadEcho::TYPE,
+		MousepadKeyboardState::TYPE,
+	];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[
+		MousepadRequest::TYPE,
+		MousepadEcho::TYPE,
+		MousepadKeyboardState::TYPE,
+	];
+}
+
+#[async_trait::async_trait]
+impl<H: MousepadHandler + Sync + Send> Plugin for MousepadPlugin<H> {
+	async fn handle_packet(&mut self, _device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		match packet.packet_type.as_str() {
+			MousepadRequest::TYPE => {
+				self.handler
+					.handle_mousepad_request(json::from_value(packet.body)?)
+					.await;
+			}
+			MousepadEcho::TYPE => {
+				self.handler
+					.handle_mousepad_echo(json::from_value(packet.body)?)
+					.await;
+			}
+			_ => {
+				self.handler
+					.handle_mousepad_keyboard_state(json::from_value(packet.body)?)
+					.await;
+			}
+		}
+		Ok(())
+	}
+}
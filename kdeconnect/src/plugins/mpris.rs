@@ -0,0 +1,139 @@
+use std::{os::unix::fs::MetadataExt, pin::Pin};
+
+use log::error;
+use serde_json as json;
+use tokio::{fs::File, io::AsyncRead};
+
+use crate::{
+	make_packet, make_packet_payload,
+	packets::{
+		Mpris, MprisPlayer, MprisRequest, MprisRequestAction, Packet, PacketPayloadTransferInfo,
+		PacketType,
+	},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait MprisHandler {
+	async fn handle_mpris_player_list(&mut self, list: Vec<String>);
+	async fn handle_mpris_player_info(&mut self, player: MprisPlayer);
+	async fn handle_mpris_player_album_art(
+		&mut self,
+		player: String,
+		art: Pin<Box<dyn AsyncRead + Sync + Send>>,
+	);
+	async fn handle_mpris_player_action(&mut self, action: MprisRequestAction);
+	async fn get_mpris_player_list(&mut self) -> Vec<String>;
+	async fn get_mpris_player(&mut self, player: String) -> Option<MprisPlayer>;
+}
+
+pub struct MprisPlugin<H> {
+	handler: H,
+}
+
+impl<H> MprisPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for MprisPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[Mpris::TYPE, MprisRequest::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[Mpris::TYPE, MprisRequest::TYPE];
+}
+
+impl<H: MprisHandler + Sync + Send> MprisPlugin<H> {
+	async fn send_album_art(
+		&self,
+		device: &PluginDevice<'_>,
+		player: String,
+		url: String,
+	) -> Result<()> {
+		let art = File::open(url.trim_start_matches("file://")).await?;
+		let size = art.metadata().await?.size();
+		let (port, fut) = device.create_payload(art).await?;
+		let packet = Mpris::TransferringArt {
+			player,
+			album_art_url: url,
+			transferring_album_art: true,
+		};
+		device
+			.send_packet(&make_packet_payload!(packet, size as i64, port))
+			.await?;
+		tokio::spawn(fut);
+		Ok(())
+	}
+}
+
+#[async_trait::async_trait]
+impl<H: MprisHandler + Sync + Send> Plugin for MprisPlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		if packet.packet_type == Mpris::TYPE {
+			let mpris: Mpris = json::from_value(packet.body)?;
+			match mpris {
+				Mpris::List { player_list, .. } => {
+					self.handler.handle_mpris_player_list(player_list).await;
+				}
+				Mpris::TransferringArt {
+					player,
+					album_art_url: _,
+					transferring_album_art,
+				} => {
+					if transferring_album_art
+						&& let Some(transfer_info) = packet.payload_transfer_info
+					{
+						self.handler
+							.handle_mpris_player_album_art(
+								player,
+								device
+									.get_payload(transfer_info, packet.payload_size)
+									.await?,
+							)
+							.await;
+					}
+				}
+				Mpris::Info(player) => {
+					self.handler.handle_mpris_player_info(player).await;
+				}
+			}
+			return Ok(());
+		}
+
+		let req: MprisRequest = json::from_value(packet.body)?;
+		match req {
+			MprisRequest::List { .. } => {
+				let packet = Mpris::List {
+					player_list: self.handler.get_mpris_player_list().await,
+					supports_album_art_payload: true,
+				};
+				device.send_packet(&make_packet!(packet)).await?;
+			}
+			MprisRequest::PlayerRequest {
+				player,
+				request_album_art,
+				..
+			} => {
+				if let Some(player_info) = self.handler.get_mpris_player(player.clone()).await {
+					if let Some(url) = request_album_art
+						&& url.starts_with("file://")
+						&& player_info
+							.album_art_url
+							.as_ref()
+							.map(|x| *x == url)
+							.unwrap_or(false) && let Err(e) =
+						self.send_album_art(device, player, url).await
+					{
+						error!("failed to send album art: {:?}", e);
+					}
+					let packet = Mpris::Info(player_info);
+					device.send_packet(&make_packet!(packet)).await?;
+				}
+			}
+			MprisRequest::Action(action) => {
+				self.handler.handle_mpris_player_action(action).await;
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,106 @@
+use std::pin::Pin;
+
+use serde_json as json;
+use tokio::io::AsyncRead;
+
+use crate::{
+	make_packet,
+	packets::{
+		Notification, NotificationAction, NotificationReply, NotificationRequest, Packet,
+		PacketType,
+	},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait NotificationHandler {
+	async fn handle_notification(
+		&mut self,
+		notification: Notification,
+		icon: Option<Pin<Box<dyn AsyncRead + Sync + Send>>>,
+	);
+	async fn handle_notification_cancel(&mut self, id: String);
+	async fn handle_notification_dismiss(&mut self, id: String);
+	async fn handle_notification_action(&mut self, id: String, action: String);
+	async fn handle_notification_reply(&mut self, request_reply_id: String, message: String);
+	async fn get_notifications(&mut self) -> Vec<Notification>;
+}
+
+pub struct NotificationPlugin<H> {
+	handler: H,
+}
+
+impl<H> NotificationPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for NotificationPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[
+		Notification::TYPE,
+		NotificationRequest::TYPE,
+		NotificationAction::TYPE,
+		NotificationReply::TYPE,
+	];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[
+		Notification::TYPE,
+		NotificationRequest::TYPE,
+		NotificationAction::TYPE,
+		NotificationReply::TYPE,
+	];
+}
+
+#[async_trait::async_trait]
+impl<H: NotificationHandler + Sync + Send> Plugin for NotificationPlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		match packet.packet_type.as_str() {
+			Notification::TYPE => {
+				let notification: Notification = json::from_value(packet.body)?;
+				if notification.is_cancel.unwrap_or(false) {
+					self.handler
+						.handle_notification_cancel(notification.id)
+						.await;
+				} else {
+					let icon = if let Some(transfer_info) = packet.payload_transfer_info {
+						Some(
+							device
+								.get_payload(transfer_info, packet.payload_size)
+								.await?,
+						)
+					} else {
+						None
+					};
+					self.handler.handle_notification(notification, icon).await;
+				}
+			}
+			NotificationRequest::TYPE => {
+				let request: NotificationRequest = json::from_value(packet.body)?;
+				match request {
+					NotificationRequest::Request { .. } => {
+						for notification in self.handler.get_notifications().await {
+							device.send_packet(&make_packet!(notification)).await?;
+						}
+					}
+					NotificationRequest::Dismiss { cancel } => {
+						self.handler.handle_notification_dismiss(cancel).await;
+					}
+				}
+			}
+			NotificationAction::TYPE => {
+				let action: NotificationAction = json::from_value(packet.body)?;
+				self.handler
+					.handle_notification_action(action.key, action.action)
+					.await;
+			}
+			_ => {
+				let reply: NotificationReply = json::from_value(packet.body)?;
+				self.handler
+					.handle_notification_reply(reply.request_reply_id, reply.message)
+					.await;
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,37 @@
+use serde_json as json;
+
+use crate::{
+	make_packet,
+	packets::{Packet, PacketType, Ping},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait PingHandler {
+	async fn handle_ping(&mut self, packet: Ping);
+}
+
+pub struct PingPlugin<H> {
+	handler: H,
+}
+
+impl<H> PingPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for PingPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[Ping::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[Ping::TYPE];
+}
+
+#[async_trait::async_trait]
+impl<H: PingHandler + Sync + Send> Plugin for PingPlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		let body: Ping = json::from_value(packet.body)?;
+		self.handler.handle_ping(body.clone()).await;
+		device.send_packet(&make_packet!(body)).await
+	}
+}
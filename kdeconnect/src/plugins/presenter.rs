@@ -0,0 +1,37 @@
+use serde_json as json;
+
+use crate::{
+	packets::{Packet, Presenter},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait PresenterHandler {
+	async fn handle_presenter(&mut self, packet: Presenter);
+}
+
+pub struct PresenterPlugin<H> {
+	handler: H,
+}
+
+impl<H> PresenterPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for PresenterPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[Presenter::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[Presenter::TYPE];
+}
+
+#[async_trait::async_trait]
+impl<H: PresenterHandler + Sync + Send> Plugin for PresenterPlugin<H> {
+	async fn handle_packet(&mut self, _device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		self.handler
+			.handle_presenter(json::from_value(packet.body)?)
+			.await;
+		Ok(())
+	}
+}
@@ -0,0 +1,138 @@
+use std::{collections::HashMap, sync::Arc};
+
+use log::{error, warn};
+use serde_json as json;
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::{
+	commands::{CommandOutput, CommandRegistry},
+	make_packet,
+	packets::{Packet, PacketType, RunCommand, RunCommandItem, RunCommandRequest},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait RunCommandHandler {
+	async fn handle_command_list(&mut self, command_list: HashMap<String, RunCommandItem>);
+	async fn handle_command_output(&mut self, command_id: String, output: Result<CommandOutput>);
+}
+
+pub struct RunCommandPlugin<H> {
+	// commands finish after handle_packet returns
+	handler: Arc<Mutex<H>>,
+	registry: Arc<CommandRegistry>,
+	// resends the command list when the registry changes
+	watcher: Option<JoinHandle<()>>,
+}
+
+impl<H> RunCommandPlugin<H> {
+	pub fn new(handler: H, registry: Arc<CommandRegistry>) -> Self {
+		Self {
+			handler: Arc::new(Mutex::new(handler)),
+			registry,
+			watcher: None,
+		}
+	}
+
+	fn stop_watcher(&mut self) {
+		if let Some(watcher) = self.watcher.take() {
+			watcher.abort();
+		}
+	}
+}
+
+impl<H> Drop for RunCommandPlugin<H> {
+	fn drop(&mut self) {
+		self.stop_watcher();
+	}
+}
+
+impl<H> PluginInfo for RunCommandPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] =
+		&[RunCommand::TYPE, RunCommandRequest::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] =
+		&[RunCommand::TYPE, RunCommandRequest::TYPE];
+}
+
+async fn make_command_list(registry: &CommandRegistry) -> Result<Packet> {
+	let packet = RunCommand {
+		command_list: json::to_string(&registry.list().await)?,
+	};
+	Ok(make_packet!(packet))
+}
+
+#[async_trait::async_trait]
+impl<H: RunCommandHandler + Sync + Send + 'static> Plugin for RunCommandPlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		if packet.packet_type == RunCommand::TYPE {
+			let packet: RunCommand = json::from_value(packet.body)?;
+			let list: HashMap<String, RunCommandItem> = json::from_str(&packet.command_list)?;
+			self.handler.lock().await.handle_command_list(list).await;
+			return Ok(());
+		}
+
+		let packet: RunCommandRequest = json::from_value(packet.body)?;
+		if packet.request_command_list.unwrap_or(false) {
+			device
+				.send_packet(&make_command_list(&self.registry).await?)
+				.await?;
+		} else if let Some(command_id) = packet.key {
+			// commands can run for a long time, don't block the device
+			let registry = self.registry.clone();
+			let handler = self.handler.clone();
+			tokio::spawn(async move {
+				let output = registry.run(&command_id).await;
+				if let Err(err) = output.as_ref() {
+					error!("failed to run command {:?}: {:?}", command_id, err);
+				}
+				handler
+					.lock()
+					.await
+					.handle_command_output(command_id, output)
+					.await;
+			});
+		}
+		Ok(())
+	}
+
+	async fn send_state(&mut self, device: &PluginDevice<'_>) -> Result<()> {
+		if !device.capabilities().can_send(RunCommand::TYPE) {
+			return Ok(());
+		}
+		device
+			.send_packet(&make_command_list(&self.registry).await?)
+			.await?;
+
+		if self.watcher.is_none() {
+			let registry = self.registry.clone();
+			let sender = device.sender();
+			let mut changed = registry.subscribe();
+			self.watcher = Some(tokio::spawn(async move {
+				while changed.changed().await.is_ok() {
+					let ret = async {
+						sender
+							.send_packet(&make_command_list(&registry).await?)
+							.await
+					}
+					.await;
+					if let Err(err) = ret {
+						warn!("failed to send command list: {:?}", err);
+						break;
+					}
+				}
+			}));
+		}
+		Ok(())
+	}
+
+	async fn handle_pair_status_change(&mut self, paired: bool) {
+		if !paired {
+			self.stop_watcher();
+		}
+	}
+
+	async fn handle_exit(&mut self) {
+		self.stop_watcher();
+	}
+}
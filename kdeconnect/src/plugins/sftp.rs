@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use log::error;
+use serde_json as json;
+
+use crate::{
+	make_packet,
+	packets::{Packet, PacketType, Sftp, SftpRequest},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	sftp::{SftpDescriptor, SftpServer},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait SftpHandler {
+	async fn handle_sftp(&mut self, sftp: Result<SftpDescriptor>);
+}
+
+pub struct SftpPlugin<H> {
+	handler: H,
+	server: Option<Arc<dyn SftpServer + Sync + Send>>,
+}
+
+impl<H> SftpPlugin<H> {
+	// requests are refused if server is None
+	pub fn new(handler: H, server: Option<Arc<dyn SftpServer + Sync + Send>>) -> Self {
+		Self { handler, server }
+	}
+}
+
+impl<H> PluginInfo for SftpPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[Sftp::TYPE, SftpRequest::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[Sftp::TYPE, SftpRequest::TYPE];
+}
+
+#[async_trait::async_trait]
+impl<H: SftpHandler + Sync + Send> Plugin for SftpPlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		if packet.packet_type == Sftp::TYPE {
+			let sftp: Sftp = json::from_value(packet.body)?;
+			self.handler.handle_sftp(sftp.try_into()).await;
+			return Ok(());
+		}
+
+		let request: SftpRequest = json::from_value(packet.body)?;
+		if request.start_browsing {
+			let packet = match self.server.as_ref() {
+				Some(server) => match server.start().await {
+					// the other side connects to the address it reached us on
+					Ok(info) => info.into_packet(device.local_ip()),
+					Err(err) => {
+						error!("failed to start sftp server: {:?}", err);
+						Sftp::Error {
+							error_message: err.to_string(),
+						}
+					}
+				},
+				None => Sftp::Error {
+					error_message: "Sftp is not supported".to_string(),
+				},
+			};
+			device.send_packet(&make_packet!(packet)).await?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,365 @@
+use std::{pin::Pin, sync::Arc, time::Duration};
+
+use log::warn;
+use serde_json as json;
+use tokio::{io::AsyncRead, sync::Mutex, time::Instant};
+
+use crate::{
+	packets::{
+		Packet, PacketPayloadTransferInfo, ShareRequest, ShareRequestFile, ShareRequestUpdate,
+	},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	transfer::TransferHandle,
+	Result,
+};
+
+// devices send the next file right after the last one, a longer gap means the rest of the share was
+// cancelled on the device
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[async_trait::async_trait]
+pub trait ShareHandler {
+	async fn handle_multi_file_share(&mut self, packet: ShareRequestUpdate);
+	// the file is marked as failed in its session if this returns an error
+	async fn handle_file_share(
+		&mut self,
+		packet: ShareRequestFile,
+		size: i64,
+		data: Pin<Box<dyn AsyncRead + Sync + Send>>,
+		transfer: TransferHandle,
+		session: &ShareSession,
+	) -> Result<()>;
+	// called once every file of the session was handled, the device stopped sending files or it
+	// disconnected
+	async fn handle_share_session_finished(&mut self, _session: ShareSession) {}
+	async fn handle_url_share(&mut self, url: String);
+	async fn handle_text_share(&mut self, text: String);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareFileStatus {
+	Transferring,
+	Finished,
+	Failed,
+}
+
+#[derive(Clone)]
+pub struct ShareFile {
+	pub name: String,
+	pub status: ShareFileStatus,
+	pub transfer: TransferHandle,
+}
+
+struct ShareSessionState {
+	number_of_files: usize,
+	files: Vec<ShareFile>,
+}
+
+/// Files a device shares together, a single file gets a session of its own.
+#[derive(Clone)]
+pub struct ShareSession {
+	state: Arc<Mutex<ShareSessionState>>,
+	transfer: TransferHandle,
+}
+
+impl ShareSession {
+	fn new(number_of_files: Option<i32>, total_payload_size: Option<i64>) -> Self {
+		Self {
+			state: Arc::new(Mutex::new(ShareSessionState {
+				number_of_files: file_count(number_of_files),
+				files: Vec::new(),
+			})),
+			transfer: TransferHandle::new(total_payload_size.unwrap_or(-1)),
+		}
+	}
+
+	// progress of all files together, cancelling it fails the files that are left
+	pub fn transfer(&self) -> &TransferHandle {
+		&self.transfer
+	}
+
+	pub async fn number_of_files(&self) -> usize {
+		self.state.lock().await.number_of_files
+	}
+
+	// files in the order they arrived
+	pub async fn files(&self) -> Vec<ShareFile> {
+		self.state.lock().await.files.clone()
+	}
+
+	pub async fn is_finished(&self) -> bool {
+		self.state.lock().await.is_finished()
+	}
+
+	// the device added files to the share while it was running
+	async fn update(&self, update: &ShareRequestUpdate) {
+		let mut state = self.state.lock().await;
+		state.number_of_files = file_count(update.number_of_files).max(state.files.len());
+		if let Some(total) = update.total_payload_size {
+			self.transfer.set_total(u64::try_from(total).ok());
+		}
+	}
+
+	async fn add_file(&self, name: String, size: i64) -> (usize, TransferHandle) {
+		let mut state = self.state.lock().await;
+		let transfer = self.transfer.child(size);
+		state.files.push(ShareFile {
+			name,
+			status: ShareFileStatus::Transferring,
+			transfer: transfer.clone(),
+		});
+		// devices may send more files than they announced
+		state.number_of_files = state.number_of_files.max(state.files.len());
+		(state.files.len() - 1, transfer)
+	}
+
+	// returns whether this was the last file
+	async fn set_status(&self, idx: usize, status: ShareFileStatus) -> bool {
+		let mut state = self.state.lock().await;
+		state.files[idx].status = status;
+		state.is_finished()
+	}
+}
+
+impl ShareSessionState {
+	fn is_finished(&self) -> bool {
+		self.files.len() >= self.number_of_files
+			&& self
+				.files
+				.iter()
+				.all(|x| x.status != ShareFileStatus::Transferring)
+	}
+}
+
+fn file_count(number_of_files: Option<i32>) -> usize {
+	number_of_files
+		.and_then(|x| usize::try_from(x).ok())
+		.unwrap_or(1)
+		.max(1)
+}
+
+pub struct SharePlugin<H> {
+	handler: H,
+	// files of a share arrive one after another
+	session: Option<ShareSession>,
+	// when the device last added to the session
+	last_activity: Instant,
+	idle_timeout: Duration,
+}
+
+impl<H> SharePlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self {
+			handler,
+			session: None,
+			last_activity: Instant::now(),
+			idle_timeout: SESSION_IDLE_TIMEOUT,
+		}
+	}
+}
+
+impl<H> PluginInfo for SharePlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] =
+		&[ShareRequest::TYPE, ShareRequestUpdate::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[ShareRequest::TYPE];
+}
+
+impl<H: ShareHandler + Sync + Send> SharePlugin<H> {
+	// devices announce how many files they share but don't say when the rest was cancelled, the
+	// files that arrived would never be reported and later shares would be added to the session
+	async fn finish_idle_session(&mut self) {
+		if self.timeout().is_some_and(|x| x <= Instant::now())
+			&& let Some(session) = self.session.take()
+		{
+			warn!(
+				"share session ended after {} of {} files",
+				session.files().await.len(),
+				session.number_of_files().await
+			);
+			self.handler.handle_share_session_finished(session).await;
+		}
+	}
+
+	async fn handle_file(
+		&mut self,
+		device: &PluginDevice<'_>,
+		file: ShareRequestFile,
+		size: i64,
+		transfer_info: PacketPayloadTransferInfo,
+	) {
+		let session = match &self.session {
+			Some(session) => session.clone(),
+			None => {
+				// the first file says how many there are if the update packet wasn't sent
+				let session =
+					ShareSession::new(file.number_of_files, file.total_payload_size.or(Some(size)));
+				self.session = Some(session.clone());
+				session
+			}
+		};
+		let (idx, transfer) = session.add_file(file.filename.clone(), size).await;
+
+		let ret = match device
+			.get_payload_tracked(transfer_info, transfer.clone())
+			.await
+		{
+			Ok(data) => {
+				self.handler
+					.handle_file_share(file, size, data, transfer, &session)
+					.await
+			}
+			Err(err) => Err(err),
+		};
+		let status = match ret {
+			Ok(()) => ShareFileStatus::Finished,
+			Err(err) => {
+				warn!("failed to recieve shared file: {:?}", err);
+				ShareFileStatus::Failed
+			}
+		};
+
+		self.last_activity = Instant::now();
+		if session.set_status(idx, status).await {
+			self.session = None;
+			self.handler.handle_share_session_finished(session).await;
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl<H: ShareHandler + Sync + Send> Plugin for SharePlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		self.finish_idle_session().await;
+		if packet.packet_type == ShareRequestUpdate::TYPE {
+			let update: ShareRequestUpdate = json::from_value(packet.body)?;
+			match &self.session {
+				Some(session) => session.update(&update).await,
+				None => {
+					self.session = Some(ShareSession::new(
+						update.number_of_files,
+						update.total_payload_size,
+					))
+				}
+			}
+			self.last_activity = Instant::now();
+			self.handler.handle_multi_file_share(update).await;
+			return Ok(());
+		}
+
+		// weird bug, fails to deser ShareRequestFile variant so we do it
+		// manually
+		let request: ShareRequest = if let Ok(request_file) = json::from_value(packet.body.clone())
+		{
+			ShareRequest::File(request_file)
+		} else {
+			json::from_value(packet.body)?
+		};
+		if let Some(transfer_info) = packet.payload_transfer_info
+			&& let Some(size) = packet.payload_size
+			&& let ShareRequest::File(file) = request
+		{
+			self.handle_file(device, file, size, transfer_info).await;
+		} else {
+			match request {
+				ShareRequest::Text { text } => {
+					self.handler.handle_text_share(text).await;
+				}
+				ShareRequest::Url { url } => {
+					self.handler.handle_url_share(url).await;
+				}
+				ShareRequest::File(_) => {} // ignore - no payload transfer info
+			}
+		}
+		Ok(())
+	}
+
+	fn timeout(&self) -> Option<Instant> {
+		self.session
+			.as_ref()
+			.map(|_| self.last_activity + self.idle_timeout)
+	}
+
+	async fn handle_timeout(&mut self) {
+		self.finish_idle_session().await;
+	}
+
+	async fn handle_exit(&mut self) {
+		// files that never arrived stay missing from the session
+		if let Some(session) = self.session.take() {
+			self.handler.handle_share_session_finished(session).await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Default)]
+	struct TestHandler {
+		finished: Vec<ShareSession>,
+	}
+
+	#[async_trait::async_trait]
+	impl ShareHandler for TestHandler {
+		async fn handle_multi_file_share(&mut self, _: ShareRequestUpdate) {}
+
+		async fn handle_file_share(
+			&mut self,
+			_: ShareRequestFile,
+			_: i64,
+			_: Pin<Box<dyn AsyncRead + Sync + Send>>,
+			_: TransferHandle,
+			_: &ShareSession,
+		) -> Result<()> {
+			Ok(())
+		}
+
+		async fn handle_share_session_finished(&mut self, session: ShareSession) {
+			self.finished.push(session);
+		}
+
+		async fn handle_url_share(&mut self, _: String) {}
+
+		async fn handle_text_share(&mut self, _: String) {}
+	}
+
+	#[tokio::test]
+	async fn session_finishes_once_every_file_is_done() {
+		let session = ShareSession::new(Some(1), None);
+		let (first, _) = session.add_file("a".to_string(), 1).await;
+		// devices may send more files than they announced
+		let (second, _) = session.add_file("b".to_string(), 1).await;
+		assert_eq!(session.number_of_files().await, 2);
+
+		// failed files count as done
+		assert!(!session.set_status(first, ShareFileStatus::Failed).await);
+		assert!(session.set_status(second, ShareFileStatus::Finished).await);
+		let statuses: Vec<_> = session.files().await.iter().map(|x| x.status).collect();
+		assert_eq!(
+			statuses,
+			[ShareFileStatus::Failed, ShareFileStatus::Finished]
+		);
+	}
+
+	#[tokio::test]
+	async fn idle_session_finishes_on_timeout() {
+		let mut plugin = SharePlugin::new(TestHandler::default());
+		assert!(plugin.timeout().is_none());
+
+		let session = ShareSession::new(Some(3), None);
+		session.add_file("a".to_string(), 1).await;
+		plugin.session = Some(session);
+		assert!(plugin.timeout().unwrap() > Instant::now());
+		plugin.handle_timeout().await;
+		assert!(plugin.handler.finished.is_empty());
+
+		// the device stopped sending files
+		plugin.idle_timeout = Duration::ZERO;
+		plugin.handle_timeout().await;
+		assert!(plugin.session.is_none());
+		assert!(plugin.timeout().is_none());
+		assert_eq!(plugin.handler.finished.len(), 1);
+		assert_eq!(plugin.handler.finished[0].files().await.len(), 1);
+	}
+}
@@ -0,0 +1,145 @@
+use std::pin::Pin;
+
+use serde_json as json;
+use tokio::io::AsyncRead;
+
+use crate::{
+	device::DevicePayload,
+	make_packet, make_packet_payload,
+	packets::{
+		Packet, PacketPayloadTransferInfo, PacketType, SmsAttachmentFile, SmsMessage, SmsMessages,
+		SmsRequest, SmsRequestAttachment, SmsRequestConversation, SmsRequestConversations,
+	},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+// kdeconnect-android sends version 2 messages and accepts version 2 requests
+pub(crate) const SMS_MESSAGES_VERSION: i32 = 2;
+pub(crate) const SMS_REQUEST_VERSION: i32 = 2;
+
+#[async_trait::async_trait]
+pub trait SmsHandler {
+	async fn handle_sms_messages(&mut self, messages: SmsMessages);
+	async fn handle_sms_attachment(
+		&mut self,
+		unique_identifier: String,
+		size: i64,
+		data: Pin<Box<dyn AsyncRead + Sync + Send>>,
+	);
+	async fn handle_sms_request(&mut self, request: SmsRequest);
+	// latest message of every conversation
+	async fn get_sms_conversations(&mut self) -> Vec<SmsMessage>;
+	async fn get_sms_conversation(
+		&mut self,
+		thread_id: i64,
+		range_start_timestamp: Option<i64>,
+		number_to_request: Option<i64>,
+	) -> Vec<SmsMessage>;
+	async fn get_sms_attachment(
+		&mut self,
+		part_id: i64,
+		unique_identifier: String,
+	) -> Option<DevicePayload<Pin<Box<dyn AsyncRead + Sync + Send>>>>;
+}
+
+pub struct SmsPlugin<H> {
+	handler: H,
+}
+
+impl<H> SmsPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for SmsPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] = &[
+		SmsRequestConversations::TYPE,
+		SmsRequestConversation::TYPE,
+		SmsMessages::TYPE,
+		SmsRequestAttachment::TYPE,
+		SmsAttachmentFile::TYPE,
+		SmsRequest::TYPE,
+	];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] = &[
+		SmsRequestConversations::TYPE,
+		SmsRequestConversation::TYPE,
+		SmsMessages::TYPE,
+		SmsRequestAttachment::TYPE,
+		SmsAttachmentFile::TYPE,
+		SmsRequest::TYPE,
+	];
+}
+
+#[async_trait::async_trait]
+impl<H: SmsHandler + Sync + Send> Plugin for SmsPlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		match packet.packet_type.as_str() {
+			SmsRequestConversations::TYPE => {
+				let packet = SmsMessages {
+					messages: self.handler.get_sms_conversations().await,
+					version: Some(SMS_MESSAGES_VERSION),
+				};
+				device.send_packet(&make_packet!(packet)).await?;
+			}
+			SmsRequestConversation::TYPE => {
+				let request: SmsRequestConversation = json::from_value(packet.body)?;
+				let packet = SmsMessages {
+					messages: self
+						.handler
+						.get_sms_conversation(
+							request.thread_id,
+							request.range_start_timestamp,
+							request.number_to_request,
+						)
+						.await,
+					version: Some(SMS_MESSAGES_VERSION),
+				};
+				device.send_packet(&make_packet!(packet)).await?;
+			}
+			SmsMessages::TYPE => {
+				self.handler
+					.handle_sms_messages(json::from_value(packet.body)?)
+					.await;
+			}
+			SmsRequestAttachment::TYPE => {
+				let request: SmsRequestAttachment = json::from_value(packet.body)?;
+				if let Some(attachment) = self
+					.handler
+					.get_sms_attachment(request.part_id, request.unique_identifier.clone())
+					.await
+				{
+					let (port, fut) = device.create_payload(attachment.buf).await?;
+					let packet = SmsAttachmentFile {
+						filename: request.unique_identifier,
+					};
+					device
+						.send_packet(&make_packet_payload!(packet, attachment.size, port))
+						.await?;
+					tokio::spawn(fut);
+				}
+			}
+			SmsAttachmentFile::TYPE => {
+				let file: SmsAttachmentFile = json::from_value(packet.body)?;
+				if let Some(transfer_info) = packet.payload_transfer_info
+					&& let Some(size) = packet.payload_size
+				{
+					self.handler
+						.handle_sms_attachment(
+							file.filename,
+							size,
+							device.get_payload(transfer_info, Some(size)).await?,
+						)
+						.await;
+				}
+			}
+			_ => {
+				self.handler
+					.handle_sms_request(json::from_value(packet.body)?)
+					.await;
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,61 @@
+use serde_json as json;
+
+use crate::{
+	make_packet,
+	packets::{Packet, PacketType, SystemVolume, SystemVolumeRequest, SystemVolumeStream},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait SystemVolumeHandler {
+	async fn handle_system_volume(&mut self, packet: SystemVolume);
+	async fn handle_system_volume_request(&mut self, packet: SystemVolumeRequest);
+	async fn get_system_volume(&mut self) -> Vec<SystemVolumeStream>;
+}
+
+pub struct SystemVolumePlugin<H> {
+	handler: H,
+}
+
+impl<H> SystemVolumePlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for SystemVolumePlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] =
+		&[SystemVolume::TYPE, SystemVolumeRequest::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] =
+		&[SystemVolume::TYPE, SystemVolumeRequest::TYPE];
+}
+
+#[async_trait::async_trait]
+impl<H: SystemVolumeHandler + Sync + Send> Plugin for SystemVolumePlugin<H> {
+	async fn handle_packet(&mut self, device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		match packet.packet_type.as_str() {
+			SystemVolume::TYPE => {
+				self.handler
+					.handle_system_volume(json::from_value(packet.body)?)
+					.await;
+			}
+			_ => {
+				let request: SystemVolumeRequest = json::from_value(packet.body)?;
+				if request.request_sinks.unwrap_or(false) {
+					self.send_state(device).await?;
+				} else {
+					self.handler.handle_system_volume_request(request).await;
+				}
+			}
+		}
+		Ok(())
+	}
+
+	async fn send_state(&mut self, device: &PluginDevice<'_>) -> Result<()> {
+		let system_volume = SystemVolume::List {
+			sink_list: self.handler.get_system_volume().await,
+		};
+		device.send_packet(&make_packet!(system_volume)).await
+	}
+}
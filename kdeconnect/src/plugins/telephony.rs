@@ -0,0 +1,45 @@
+use serde_json as json;
+
+use crate::{
+	packets::{Packet, Telephony, TelephonyRequestMute},
+	plugins::{Plugin, PluginDevice, PluginInfo},
+	Result,
+};
+
+#[async_trait::async_trait]
+pub trait TelephonyHandler {
+	async fn handle_telephony(&mut self, packet: Telephony);
+	async fn handle_telephony_mute_request(&mut self);
+}
+
+pub struct TelephonyPlugin<H> {
+	handler: H,
+}
+
+impl<H> TelephonyPlugin<H> {
+	pub fn new(handler: H) -> Self {
+		Self { handler }
+	}
+}
+
+impl<H> PluginInfo for TelephonyPlugin<H> {
+	const INCOMING_CAPABILITIES: &'static [&'static str] =
+		&[Telephony::TYPE, TelephonyRequestMute::TYPE];
+	const OUTGOING_CAPABILITIES: &'static [&'static str] =
+		&[Telephony::TYPE, TelephonyRequestMute::TYPE];
+}
+
+#[async_trait::async_trait]
+impl<H: TelephonyHandler + Sync + Send> Plugin for TelephonyPlugin<H> {
+	async fn handle_packet(&mut self, _device: &PluginDevice<'_>, packet: Packet) -> Result<()> {
+		match packet.packet_type.as_str() {
+			Telephony::TYPE => {
+				self.handler
+					.handle_telephony(json::from_value(packet.body)?)
+					.await;
+			}
+			_ => self.handler.handle_telephony_mute_request().await,
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+
+use serde_json::{json, Value};
+use tokio::sync::{oneshot, Mutex, Notify};
+
+use crate::{
+	packets::{MousepadRequest, Packet, Presenter},
+	Result,
+};
+
+// body fields of mousepad and presenter packets that only move the cursor
+const MOVEMENT_FIELDS: [&str; 3] = ["dx", "dy", "scroll"];
+
+pub(crate) struct QueuedPacket {
+	pub(crate) packet: Packet,
+	// everyone whose packet got coalesced into this one
+	pub(crate) responders: Vec<oneshot::Sender<Result<()>>>,
+}
+
+#[derive(Default)]
+struct QueueState {
+	normal: VecDeque<QueuedPacket>,
+	// input packets, sent after everything else
+	input: VecDeque<QueuedPacket>,
+	closed: bool,
+}
+
+impl QueueState {
+	fn len(&self) -> usize {
+		self.normal.len() + self.input.len()
+	}
+}
+
+// outgoing packets of a device, bounded so a stalled device can't grow it forever
+pub(crate) struct SendQueue {
+	state: Mutex<QueueState>,
+	capacity: usize,
+	pushed: Notify,
+	popped: Notify,
+}
+
+impl SendQueue {
+	pub(crate) fn new(capacity: usize) -> Self {
+		Self {
+			state: Mutex::new(QueueState::default()),
+			capacity,
+			pushed: Notify::new(),
+			popped: Notify::new(),
+		}
+	}
+
+	// waits for room unless the packet can be coalesced, the receiver gets the result of sending
+	pub(crate) async fn push(&self, packet: Packet) -> oneshot::Receiver<Result<()>> {
+		let (tx, rx) = oneshot::channel();
+		let is_input = is_input(&packet);
+		loop {
+			// created before checking so a pop in between isn't missed
+			let popped = self.popped.notified();
+			{
+				let mut state = self.state.lock().await;
+				if state.closed {
+					return rx;
+				}
+				if is_input
+					&& let Some(queued) = state.input.back_mut()
+					&& coalesce(&mut queued.packet, &packet)
+				{
+					queued.responders.push(tx);
+					return rx;
+				}
+				if state.len() < self.capacity {
+					let queue = if is_input {
+						&mut state.input
+					} else {
+						&mut state.normal
+					};
+					queue.push_back(QueuedPacket {
+						packet,
+						responders: vec![tx],
+					});
+					self.pushed.notify_one();
+					return rx;
+				}
+			}
+			popped.await;
+		}
+	}
+
+	pub(crate) async fn pop(&self) -> QueuedPacket {
+		loop {
+			{
+				let mut state = self.state.lock().await;
+				let queued = match state.normal.pop_front() {
+					Some(queued) => Some(queued),
+					None => state.input.pop_front(),
+				};
+				if let Some(queued) = queued {
+					self.popped.notify_waiters();
+					return queued;
+				}
+			}
+			self.pushed.notified().await;
+		}
+	}
+
+	// queued and future packets fail to send
+	pub(crate) async fn close(&self) {
+		let mut state = self.state.lock().await;
+		state.closed = true;
+		state.normal.clear();
+		state.input.clear();
+		self.popped.notify_waiters();
+	}
+}
+
+// mousepad and presenter packets keep their order relative to each other
+fn is_input(packet: &Packet) -> bool {
+	packet.packet_type == MousepadRequest::TYPE || packet.packet_type == Presenter::TYPE
+}
+
+fn is_movement(packet: &Packet) -> bool {
+	is_input(packet)
+		&& packet.payload_size.is_none()
+		&& packet.body.as_object().is_some_and(|body| {
+			body.iter()
+				.all(|(key, value)| value.is_null() || MOVEMENT_FIELDS.contains(&key.as_str()))
+		})
+}
+
+// movements that haven't been sent yet are added together
+fn coalesce(queued: &mut Packet, packet: &Packet) -> bool {
+	if queued.packet_type != packet.packet_type
+		|| !is_movement(queued)
+		|| !is_movement(packet)
+		|| queued.body.get("scroll") != packet.body.get("scroll")
+	{
+		return false;
+	}
+	for field in ["dx", "dy"] {
+		let sum = [&queued.body, &packet.body]
+			.iter()
+			.filter_map(|x| x.get(field).and_then(Value::as_f64))
+			.reduce(|a, b| a + b);
+		// fields neither packet had stay missing
+		if let Some(sum) = sum {
+			queued.body[field] = json!(sum);
+		}
+	}
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn mousepad(body: Value) -> Packet {
+		Packet::new(MousepadRequest::TYPE, body)
+	}
+
+	#[test]
+	fn coalesce_only_adds_present_fields() {
+		let mut queued = mousepad(json!({ "dx": 1.0 }));
+		assert!(coalesce(&mut queued, &mousepad(json!({ "dx": 2.0 }))));
+		assert_eq!(queued.body, json!({ "dx": 3.0 }));
+
+		assert!(coalesce(&mut queued, &mousepad(json!({ "dy": 1.0 }))));
+		assert_eq!(queued.body, json!({ "dx": 3.0, "dy": 1.0 }));
+
+		// scrolls and movements aren't added together
+		assert!(!coalesce(
+			&mut queued,
+			&mousepad(json!({ "dy": 1.0, "scroll": true }))
+		));
+	}
+}
@@ -0,0 +1,97 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{
+	packets::{Sftp, SftpInfo},
+	KdeConnectError, Result,
+};
+
+#[cfg(feature = "sftp")]
+mod embedded;
+#[cfg(feature = "sftp")]
+pub use embedded::EmbeddedSftpServer;
+
+#[derive(Clone, Debug)]
+pub struct SftpPath {
+	pub path: String,
+	pub name: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct SftpServerInfo {
+	pub port: u16,
+	pub user: String,
+	pub password: String,
+	// common root of all paths
+	pub path: String,
+	pub paths: Vec<SftpPath>,
+}
+
+impl SftpServerInfo {
+	pub(crate) fn into_packet(self, ip: IpAddr) -> Sftp {
+		let (multi_paths, path_names) = self.paths.into_iter().map(|x| (x.path, x.name)).unzip();
+		Sftp::Info(SftpInfo {
+			ip: ip.to_string(),
+			port: self.port,
+			user: self.user,
+			password: self.password,
+			path: self.path,
+			multi_paths: Some(multi_paths),
+			path_names: Some(path_names),
+		})
+	}
+}
+
+// answers kdeconnect.sftp.request, implement this to use a different sftp server
+#[async_trait::async_trait]
+pub trait SftpServer {
+	// called on every request, an already running server should be reused
+	async fn start(&self) -> Result<SftpServerInfo>;
+}
+
+// everything needed to connect to the sftp server of the other device
+#[derive(Clone, Debug)]
+pub struct SftpDescriptor {
+	pub addr: SocketAddr,
+	pub user: String,
+	pub password: String,
+	pub path: String,
+	pub paths: Vec<SftpPath>,
+}
+
+impl TryFrom<Sftp> for SftpDescriptor {
+	type Error = KdeConnectError;
+
+	fn try_from(packet: Sftp) -> Result<Self> {
+		let info = match packet {
+			Sftp::Info(info) => info,
+			Sftp::Error { error_message } => return Err(KdeConnectError::SftpError(error_message)),
+		};
+
+		let ip: IpAddr = info
+			.ip
+			.parse()
+			.map_err(|_| KdeConnectError::InvalidSftpInfo)?;
+
+		// older clients only send path
+		let paths = match (info.multi_paths, info.path_names) {
+			(Some(paths), Some(names)) if paths.len() == names.len() => paths
+				.into_iter()
+				.zip(names)
+				.map(|(path, name)| SftpPath { path, name })
+				.collect(),
+			(Some(_), Some(_)) => return Err(KdeConnectError::InvalidSftpInfo),
+			_ => vec![SftpPath {
+				path: info.path.clone(),
+				name: info.path.clone(),
+			}],
+		};
+
+		Ok(Self {
+			addr: SocketAddr::new(ip, info.port),
+			user: info.user,
+			password: info.password,
+			path: info.path,
+			paths,
+		})
+	}
+}
@@ -0,0 +1,674 @@
+use std::{
+	collections::HashMap,
+	io::{ErrorKind, SeekFrom},
+	net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+	os::unix::fs::PermissionsExt,
+	path::{Path, PathBuf},
+	sync::Arc,
+	time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use russh::{
+	keys::key::KeyPair,
+	server::{Auth, Config, Handler, Msg, Server, Session},
+	Channel, ChannelId, MethodSet,
+};
+use russh_sftp::protocol::{
+	Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version,
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+	fs::{self, OpenOptions},
+	io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+	net::TcpListener,
+	sync::Mutex,
+	task::JoinHandle,
+};
+
+use super::{SftpPath, SftpServer, SftpServerInfo};
+use crate::{util::bind_tcp_v6, KdeConnectError, Result};
+
+// same range as kdeconnect-android
+const SFTP_PORT_RANGE: std::ops::RangeInclusive<u16> = 1739..=1764;
+const SFTP_USER: &str = "kdeconnect";
+// largest read we answer, clients ask for at most 256k anyway
+const MAX_READ_LENGTH: u32 = 1024 * 1024;
+
+// serves the configured directories under a virtual root, every directory is
+// available as /<name>
+pub struct EmbeddedSftpServer {
+	roots: Arc<Vec<(String, PathBuf)>>,
+	running: Mutex<Option<(SftpServerInfo, JoinHandle<()>)>>,
+}
+
+impl EmbeddedSftpServer {
+	pub fn new(roots: Vec<(String, PathBuf)>) -> Self {
+		Self {
+			roots: Arc::new(roots),
+			running: Mutex::new(None),
+		}
+	}
+}
+
+impl Drop for EmbeddedSftpServer {
+	fn drop(&mut self) {
+		if let Some((_, task)) = self.running.get_mut().take() {
+			task.abort();
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl SftpServer for EmbeddedSftpServer {
+	async fn start(&self) -> Result<SftpServerInfo> {
+		let mut running = self.running.lock().await;
+		if let Some((info, task)) = running.as_ref()
+			&& !task.is_finished()
+		{
+			return Ok(info.clone());
+		}
+
+		let mut listener: Option<(TcpListener, u16)> = None;
+		for port in SFTP_PORT_RANGE {
+			// dual stack since the server is shared by devices on either address family
+			let free = match bind_tcp_v6(port, false) {
+				Ok(free) => Ok(free),
+				Err(_) => TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).await,
+			};
+			if let Ok(free) = free {
+				listener = Some((free, port));
+				break;
+			}
+		}
+		let (listener, port) = listener.ok_or(KdeConnectError::NoSftpPortFound)?;
+
+		let password = hex::encode(rand::random::<[u8; 16]>());
+		let config = Arc::new(Config {
+			methods: MethodSet::PASSWORD,
+			keys: vec![KeyPair::generate_ed25519().ok_or(KdeConnectError::Other)?],
+			auth_rejection_time: Duration::from_secs(1),
+			auth_rejection_time_initial: Some(Duration::ZERO),
+			inactivity_timeout: Some(Duration::from_secs(3600)),
+			..Default::default()
+		});
+
+		let mut server = SshServer {
+			roots: self.roots.clone(),
+			password: password.clone(),
+		};
+		let task = tokio::spawn(async move {
+			if let Err(err) = server.run_on_socket(config, &listener).await {
+				error!("sftp server failed: {:?}", err);
+			}
+		});
+		info!("started sftp server on port {}", port);
+
+		let info = SftpServerInfo {
+			port,
+			user: SFTP_USER.to_string(),
+			password,
+			path: "/".to_string(),
+			paths: self
+				.roots
+				.iter()
+				.map(|(name, _)| SftpPath {
+					path: format!("/{}", name),
+					name: name.clone(),
+				})
+				.collect(),
+		};
+		running.replace((info.clone(), task));
+		Ok(info)
+	}
+}
+
+struct SshServer {
+	roots: Arc<Vec<(String, PathBuf)>>,
+	password: String,
+}
+
+impl Server for SshServer {
+	type Handler = SshSession;
+
+	fn new_client(&mut self, addr: Option<SocketAddr>) -> Self::Handler {
+		debug!("new sftp client {:?}", addr);
+		SshSession {
+			roots: self.roots.clone(),
+			password: self.password.clone(),
+			channels: HashMap::new(),
+		}
+	}
+}
+
+struct SshSession {
+	roots: Arc<Vec<(String, PathBuf)>>,
+	password: String,
+	channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+#[async_trait::async_trait]
+impl Handler for SshSession {
+	type Error = russh::Error;
+
+	async fn auth_password(
+		&mut self,
+		user: &str,
+		password: &str,
+	) -> std::result::Result<Auth, Self::Error> {
+		// constant time so the password can't be guessed from how long a rejection takes
+		if user == SFTP_USER && bool::from(password.as_bytes().ct_eq(self.password.as_bytes())) {
+			Ok(Auth::Accept)
+		} else {
+			warn!("rejected sftp login for user {:?}", user);
+			Ok(Auth::Reject {
+				proceed_with_methods: None,
+			})
+		}
+	}
+
+	async fn channel_open_session(
+		&mut self,
+		channel: Channel<Msg>,
+		_: &mut Session,
+	) -> std::result::Result<bool, Self::Error> {
+		self.channels.insert(channel.id(), channel);
+		Ok(true)
+	}
+
+	async fn subsystem_request(
+		&mut self,
+		channel_id: ChannelId,
+		name: &str,
+		session: &mut Session,
+	) -> std::result::Result<(), Self::Error> {
+		if name == "sftp"
+			&& let Some(channel) = self.channels.remove(&channel_id)
+		{
+			session.channel_success(channel_id);
+			russh_sftp::server::run(channel.into_stream(), SftpSession::new(self.roots.clone()))
+				.await;
+		} else {
+			session.channel_failure(channel_id);
+		}
+		Ok(())
+	}
+}
+
+enum SftpHandle {
+	File(fs::File),
+	// entries are taken once they have been sent
+	Dir(Option<Vec<File>>),
+}
+
+struct SftpSession {
+	roots: Arc<Vec<(String, PathBuf)>>,
+	handles: HashMap<String, SftpHandle>,
+	next_handle: u64,
+}
+
+impl SftpSession {
+	fn new(roots: Arc<Vec<(String, PathBuf)>>) -> Self {
+		Self {
+			roots,
+			handles: HashMap::new(),
+			next_handle: 0,
+		}
+	}
+
+	fn add_handle(&mut self, handle: SftpHandle) -> String {
+		let id = self.next_handle.to_string();
+		self.next_handle += 1;
+		self.handles.insert(id.clone(), handle);
+		id
+	}
+
+	// resolves a path in the virtual root to a real path, None is the virtual root itself
+	async fn resolve(&self, path: &str) -> std::result::Result<Option<PathBuf>, StatusCode> {
+		let components = normalize(path);
+		let Some((name, rest)) = components.split_first() else {
+			return Ok(None);
+		};
+		let (_, root) = self
+			.roots
+			.iter()
+			.find(|(root_name, _)| root_name == name)
+			.ok_or(StatusCode::NoSuchFile)?;
+		let resolved = rest.iter().fold(root.clone(), |path, x| path.join(x));
+
+		// symlinks must not lead outside of the root
+		let root = fs::canonicalize(root).await.map_err(io_status)?;
+		let existing = match fs::canonicalize(&resolved).await {
+			Ok(path) => path,
+			// a dangling symlink would be created or written through wherever it points
+			Err(_) if is_symlink(&resolved).await => {
+				warn!(
+					"sftp client tried to access dangling symlink {:?}",
+					resolved
+				);
+				return Err(StatusCode::PermissionDenied);
+			}
+			Err(_) => match resolved.parent() {
+				Some(parent) => fs::canonicalize(parent).await.map_err(io_status)?,
+				None => return Err(StatusCode::NoSuchFile),
+			},
+		};
+		if !existing.starts_with(&root) {
+			warn!("sftp client tried to access {:?} outside of root", resolved);
+			return Err(StatusCode::PermissionDenied);
+		}
+		Ok(Some(resolved))
+	}
+
+	// operations that modify something can't target the virtual root or its directories
+	async fn resolve_mut(&self, path: &str) -> std::result::Result<PathBuf, StatusCode> {
+		if normalize(path).len() < 2 {
+			return Err(StatusCode::PermissionDenied);
+		}
+		self.resolve(path)
+			.await?
+			.ok_or(StatusCode::PermissionDenied)
+	}
+
+	async fn list_dir(&self, path: Option<PathBuf>) -> std::result::Result<Vec<File>, StatusCode> {
+		let mut files = Vec::new();
+		match path {
+			None => {
+				for (name, root) in self.roots.iter() {
+					let attrs = match fs::metadata(root).await {
+						Ok(metadata) => (&metadata).into(),
+						Err(_) => root_attrs(),
+					};
+					files.push(File::new(name.clone(), attrs));
+				}
+			}
+			Some(path) => {
+				let mut dir = fs::read_dir(path).await.map_err(io_status)?;
+				while let Some(entry) = dir.next_entry().await.map_err(io_status)? {
+					let Ok(metadata) = entry.metadata().await else {
+						continue;
+					};
+					files.push(File::new(
+						entry.file_name().to_string_lossy(),
+						(&metadata).into(),
+					));
+				}
+			}
+		}
+		Ok(files)
+	}
+
+	async fn stat_path(
+		&self,
+		path: &str,
+		follow: bool,
+	) -> std::result::Result<FileAttributes, StatusCode> {
+		match self.resolve(path).await? {
+			None => Ok(root_attrs()),
+			Some(path) if follow => Ok((&fs::metadata(path).await.map_err(io_status)?).into()),
+			Some(path) => Ok((&fs::symlink_metadata(path).await.map_err(io_status)?).into()),
+		}
+	}
+
+	fn file(&mut self, handle: &str) -> std::result::Result<&mut fs::File, StatusCode> {
+		match self.handles.get_mut(handle) {
+			Some(SftpHandle::File(file)) => Ok(file),
+			_ => Err(StatusCode::Failure),
+		}
+	}
+}
+
+impl russh_sftp::server::Handler for SftpSession {
+	type Error = StatusCode;
+
+	fn unimplemented(&self) -> Self::Error {
+		StatusCode::OpUnsupported
+	}
+
+	async fn init(
+		&mut self,
+		_: u32,
+		_: HashMap<String, String>,
+	) -> std::result::Result<Version, Self::Error> {
+		Ok(Version::new())
+	}
+
+	async fn open(
+		&mut self,
+		id: u32,
+		filename: String,
+		pflags: OpenFlags,
+		_: FileAttributes,
+	) -> std::result::Result<Handle, Self::Error> {
+		let path = self.resolve_mut(&filename).await?;
+		if is_symlink(&path).await {
+			return Err(StatusCode::PermissionDenied);
+		}
+		// the link could be swapped in after the checks, only open the path that was checked
+		let file = OpenOptions::from(std::fs::OpenOptions::from(pflags))
+			.custom_flags(libc::O_NOFOLLOW)
+			.open(path)
+			.await
+			.map_err(io_status)?;
+		Ok(Handle {
+			id,
+			handle: self.add_handle(SftpHandle::File(file)),
+		})
+	}
+
+	async fn close(&mut self, id: u32, handle: String) -> std::result::Result<Status, Self::Error> {
+		if let Some(SftpHandle::File(mut file)) = self.handles.remove(&handle) {
+			file.flush().await.map_err(io_status)?;
+		}
+		Ok(ok_status(id))
+	}
+
+	async fn read(
+		&mut self,
+		id: u32,
+		handle: String,
+		offset: u64,
+		len: u32,
+	) -> std::result::Result<Data, Self::Error> {
+		let file = self.file(&handle)?;
+		file.seek(SeekFrom::Start(offset))
+			.await
+			.map_err(io_status)?;
+		let mut data = Vec::with_capacity(len.min(MAX_READ_LENGTH) as usize);
+		file.take(len.min(MAX_READ_LENGTH) as u64)
+			.read_to_end(&mut data)
+			.await
+			.map_err(io_status)?;
+		if data.is_empty() {
+			return Err(StatusCode::Eof);
+		}
+		Ok(Data { id, data })
+	}
+
+	async fn write(
+		&mut self,
+		id: u32,
+		handle: String,
+		offset: u64,
+		data: Vec<u8>,
+	) -> std::result::Result<Status, Self::Error> {
+		let file = self.file(&handle)?;
+		file.seek(SeekFrom::Start(offset))
+			.await
+			.map_err(io_status)?;
+		file.write_all(&data).await.map_err(io_status)?;
+		Ok(ok_status(id))
+	}
+
+	async fn lstat(&mut self, id: u32, path: String) -> std::result::Result<Attrs, Self::Error> {
+		Ok(Attrs {
+			id,
+			attrs: self.stat_path(&path, false).await?,
+		})
+	}
+
+	async fn stat(&mut self, id: u32, path: String) -> std::result::Result<Attrs, Self::Error> {
+		Ok(Attrs {
+			id,
+			attrs: self.stat_path(&path, true).await?,
+		})
+	}
+
+	async fn fstat(&mut self, id: u32, handle: String) -> std::result::Result<Attrs, Self::Error> {
+		let metadata = self.file(&handle)?.metadata().await.map_err(io_status)?;
+		Ok(Attrs {
+			id,
+			attrs: (&metadata).into(),
+		})
+	}
+
+	async fn setstat(
+		&mut self,
+		id: u32,
+		path: String,
+		attrs: FileAttributes,
+	) -> std::result::Result<Status, Self::Error> {
+		let path = self.resolve_mut(&path).await?;
+		if let Some(size) = attrs.size {
+			let file = OpenOptions::new()
+				.write(true)
+				.open(&path)
+				.await
+				.map_err(io_status)?;
+			file.set_len(size).await.map_err(io_status)?;
+		}
+		if let Some(permissions) = attrs.permissions {
+			fs::set_permissions(&path, std::fs::Permissions::from_mode(permissions & 0o7777))
+				.await
+				.map_err(io_status)?;
+		}
+		Ok(ok_status(id))
+	}
+
+	async fn fsetstat(
+		&mut self,
+		id: u32,
+		handle: String,
+		attrs: FileAttributes,
+	) -> std::result::Result<Status, Self::Error> {
+		let file = self.file(&handle)?;
+		if let Some(size) = attrs.size {
+			file.set_len(size).await.map_err(io_status)?;
+		}
+		if let Some(permissions) = attrs.permissions {
+			file.set_permissions(std::fs::Permissions::from_mode(permissions & 0o7777))
+				.await
+				.map_err(io_status)?;
+		}
+		Ok(ok_status(id))
+	}
+
+	async fn opendir(&mut self, id: u32, path: String) -> std::result::Result<Handle, Self::Error> {
+		let path = self.resolve(&path).await?;
+		let files = self.list_dir(path).await?;
+		Ok(Handle {
+			id,
+			handle: self.add_handle(SftpHandle::Dir(Some(files))),
+		})
+	}
+
+	async fn readdir(&mut self, id: u32, handle: String) -> std::result::Result<Name, Self::Error> {
+		match self.handles.get_mut(&handle) {
+			Some(SftpHandle::Dir(files)) => match files.take() {
+				Some(files) if !files.is_empty() => Ok(Name { id, files }),
+				_ => Err(StatusCode::Eof),
+			},
+			_ => Err(StatusCode::Failure),
+		}
+	}
+
+	async fn remove(
+		&mut self,
+		id: u32,
+		filename: String,
+	) -> std::result::Result<Status, Self::Error> {
+		let path = self.resolve_mut(&filename).await?;
+		fs::remove_file(path).await.map_err(io_status)?;
+		Ok(ok_status(id))
+	}
+
+	async fn mkdir(
+		&mut self,
+		id: u32,
+		path: String,
+		_: FileAttributes,
+	) -> std::result::Result<Status, Self::Error> {
+		let path = self.resolve_mut(&path).await?;
+		fs::create_dir(path).await.map_err(io_status)?;
+		Ok(ok_status(id))
+	}
+
+	async fn rmdir(&mut self, id: u32, path: String) -> std::result::Result<Status, Self::Error> {
+		let path = self.resolve_mut(&path).await?;
+		fs::remove_dir(path).await.map_err(io_status)?;
+		Ok(ok_status(id))
+	}
+
+	async fn rename(
+		&mut self,
+		id: u32,
+		oldpath: String,
+		newpath: String,
+	) -> std::result::Result<Status, Self::Error> {
+		let oldpath = self.resolve_mut(&oldpath).await?;
+		let newpath = self.resolve_mut(&newpath).await?;
+		fs::rename(oldpath, newpath).await.map_err(io_status)?;
+		Ok(ok_status(id))
+	}
+
+	async fn realpath(&mut self, id: u32, path: String) -> std::result::Result<Name, Self::Error> {
+		let path = format!("/{}", normalize(&path).join("/"));
+		Ok(Name {
+			id,
+			files: vec![File::dummy(path)],
+		})
+	}
+}
+
+// resolves . and .. without touching the filesystem, .. at the root stays at the root
+fn normalize(path: &str) -> Vec<&str> {
+	let mut components = Vec::new();
+	for component in path.split('/') {
+		match component {
+			"" | "." => {}
+			".." => {
+				components.pop();
+			}
+			x => components.push(x),
+		}
+	}
+	components
+}
+
+async fn is_symlink(path: &Path) -> bool {
+	fs::symlink_metadata(path)
+		.await
+		.is_ok_and(|metadata| metadata.is_symlink())
+}
+
+fn root_attrs() -> FileAttributes {
+	let mut attrs = FileAttributes::dummy();
+	attrs.permissions = Some(0o555);
+	attrs.set_dir(true);
+	attrs
+}
+
+fn ok_status(id: u32) -> Status {
+	Status {
+		id,
+		status_code: StatusCode::Ok,
+		error_message: "Ok".to_string(),
+		language_tag: "en-US".to_string(),
+	}
+}
+
+fn io_status(err: std::io::Error) -> StatusCode {
+	match err.kind() {
+		ErrorKind::NotFound => StatusCode::NoSuchFile,
+		ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+		_ => {
+			debug!("sftp io error: {:?}", err);
+			StatusCode::Failure
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use russh::{client, keys::key::PublicKey};
+	use russh_sftp::client::SftpSession;
+
+	use super::*;
+
+	struct Client;
+
+	#[async_trait::async_trait]
+	impl client::Handler for Client {
+		type Error = russh::Error;
+
+		async fn check_server_key(
+			&mut self,
+			_: &PublicKey,
+		) -> std::result::Result<bool, Self::Error> {
+			Ok(true)
+		}
+	}
+
+	async fn connect(info: &SftpServerInfo, password: &str) -> Option<client::Handle<Client>> {
+		let config = Arc::new(client::Config::default());
+		let mut session = client::connect(config, (Ipv4Addr::LOCALHOST, info.port), Client)
+			.await
+			.unwrap();
+		session
+			.authenticate_password(&info.user, password)
+			.await
+			.unwrap()
+			.then_some(session)
+	}
+
+	#[tokio::test]
+	async fn mount_over_loopback() {
+		let root = std::env::temp_dir().join(format!(
+			"kdeconnect-sftp-{}",
+			hex::encode(rand::random::<[u8; 8]>())
+		));
+		fs::create_dir_all(root.join("dir")).await.unwrap();
+		fs::write(root.join("dir/hello.txt"), b"hello")
+			.await
+			.unwrap();
+
+		let server = EmbeddedSftpServer::new(vec![("files".to_string(), root.clone())]);
+		let info = server.start().await.unwrap();
+		assert!(SFTP_PORT_RANGE.contains(&info.port));
+		assert_eq!(info.paths[0].path, "/files");
+		// the same server is handed out while it runs
+		assert_eq!(server.start().await.unwrap().password, info.password);
+
+		assert!(connect(&info, "wrong").await.is_none());
+		let session = connect(&info, &info.password).await.unwrap();
+		let channel = session.channel_open_session().await.unwrap();
+		channel.request_subsystem(true, "sftp").await.unwrap();
+		let sftp = SftpSession::new(channel.into_stream()).await.unwrap();
+
+		let names: Vec<String> = sftp
+			.read_dir("/")
+			.await
+			.unwrap()
+			.map(|x| x.file_name())
+			.collect();
+		assert_eq!(names, ["files"]);
+		assert_eq!(sftp.read("/files/dir/hello.txt").await.unwrap(), b"hello");
+
+		let mut file = sftp.create("/files/dir/new.txt").await.unwrap();
+		file.write_all(b"written").await.unwrap();
+		file.shutdown().await.unwrap();
+		assert_eq!(
+			fs::read(root.join("dir/new.txt")).await.unwrap(),
+			b"written"
+		);
+
+		// .. stops at the virtual root and the roots themselves can't be replaced
+		assert_eq!(
+			sftp.read("/files/../../files/dir/hello.txt").await.unwrap(),
+			b"hello"
+		);
+		assert!(sftp.remove_dir("/files").await.is_err());
+		assert!(sftp.create("/outside.txt").await.is_err());
+
+		// symlinks can't be used to write outside of the root
+		let outside = root.with_extension("outside");
+		fs::symlink(&outside, root.join("dir/link")).await.unwrap();
+		assert!(sftp.create("/files/dir/link").await.is_err());
+		assert!(!fs::try_exists(&outside).await.unwrap());
+
+		sftp.close().await.unwrap();
+		fs::remove_dir_all(root).await.unwrap();
+	}
+}
@@ -0,0 +1,333 @@
+#[cfg(target_os = "ios")]
+use std::os::ios::fs::FileTimesExt;
+#[cfg(target_os = "macos")]
+use std::os::macos::fs::FileTimesExt;
+use std::{
+	collections::HashMap,
+	fs::FileTimes,
+	io::ErrorKind,
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+use log::warn;
+use tokio::{
+	fs::{self, OpenOptions},
+	io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+	sync::Mutex,
+};
+
+use crate::{packets::ShareRequestFile, KdeConnectError, Result};
+
+// leaves room for the number added on collisions within the usual 255 byte limit
+const MAX_FILE_NAME_LEN: usize = 240;
+const MAX_EXTENSION_LEN: usize = 16;
+const DEFAULT_FILE_NAME: &str = "file";
+
+/// Where a device's files are saved and how much it may save.
+#[derive(Debug, Clone)]
+pub struct StoragePolicy {
+	pub directory: PathBuf,
+	/// Bytes the device may store, `None` for no limit. Usage is only counted while the storage
+	/// exists and starts at zero again when it is recreated, such as after a restart.
+	pub quota: Option<u64>,
+}
+
+/// Saves files shared by devices. Files are written to a temporary file and only show up under
+/// their name once complete.
+pub struct FileStorage {
+	// every policy's directory has to be inside one of these
+	allowed_directories: Vec<PathBuf>,
+	default_policy: StoragePolicy,
+	policies: Mutex<HashMap<String, StoragePolicy>>,
+	usage: Mutex<HashMap<String, u64>>,
+	// picking a free name and renaming onto it has to happen at once where hard links aren't
+	// supported
+	rename_lock: Mutex<()>,
+}
+
+impl FileStorage {
+	pub fn new(directory: PathBuf) -> Self {
+		Self {
+			allowed_directories: vec![directory.clone()],
+			default_policy: StoragePolicy {
+				directory,
+				quota: None,
+			},
+			policies: Mutex::new(HashMap::new()),
+			usage: Mutex::new(HashMap::new()),
+			rename_lock: Mutex::new(()),
+		}
+	}
+
+	pub fn allow_directory(&mut self, directory: PathBuf) {
+		self.allowed_directories.push(directory);
+	}
+
+	// quota of devices without their own policy
+	pub fn set_default_quota(&mut self, quota: Option<u64>) {
+		self.default_policy.quota = quota;
+	}
+
+	// fails with KdeConnectError::DirectoryNotAllowed if the directory isn't in an allowed one
+	pub async fn set_device_policy(&self, device_id: &str, policy: StoragePolicy) -> Result<()> {
+		self.check_allowed(&policy.directory).await?;
+		self.policies
+			.lock()
+			.await
+			.insert(device_id.to_string(), policy);
+		Ok(())
+	}
+
+	pub async fn usage(&self, device_id: &str) -> u64 {
+		self.usage.lock().await.get(device_id).copied().unwrap_or(0)
+	}
+
+	/// Saves a file shared by a device and returns where it ended up. Existing files are never
+	/// replaced, a number is added to the name instead.
+	pub async fn store(
+		&self,
+		device_id: &str,
+		file: &ShareRequestFile,
+		size: i64,
+		mut data: impl AsyncRead + Unpin,
+	) -> Result<PathBuf> {
+		let policy = self
+			.policies
+			.lock()
+			.await
+			.get(device_id)
+			.cloned()
+			.unwrap_or_else(|| self.default_policy.clone());
+		let directory = self.check_allowed(&policy.directory).await?;
+
+		// the declared size is reserved up front so concurrent shares can't exceed the quota
+		let reserved = u64::try_from(size).unwrap_or(0);
+		let limit = {
+			let mut usage = self.usage.lock().await;
+			let used = usage.entry(device_id.to_string()).or_default();
+			let remaining = policy.quota.map(|x| x.saturating_sub(*used));
+			if remaining.is_some_and(|x| x < reserved) {
+				return Err(KdeConnectError::StorageQuotaExceeded);
+			}
+			*used += reserved;
+			remaining
+		};
+
+		let ret = self.write(&directory, file, &mut data, limit).await;
+
+		let mut usage = self.usage.lock().await;
+		let used = usage.entry(device_id.to_string()).or_default();
+		*used = used.saturating_sub(reserved);
+		ret.map(|(path, written)| {
+			*used += written;
+			path
+		})
+	}
+
+	async fn check_allowed(&self, directory: &Path) -> Result<PathBuf> {
+		// resolves symlinks and .. so the directory can't escape
+		let directory = fs::canonicalize(directory).await?;
+		for allowed in &self.allowed_directories {
+			if let Ok(allowed) = fs::canonicalize(allowed).await
+				&& directory.starts_with(&allowed)
+			{
+				return Ok(directory);
+			}
+		}
+		Err(KdeConnectError::DirectoryNotAllowed(directory))
+	}
+
+	async fn write(
+		&self,
+		directory: &Path,
+		file: &ShareRequestFile,
+		data: &mut (impl AsyncRead + Unpin),
+		limit: Option<u64>,
+	) -> Result<(PathBuf, u64)> {
+		let temp = directory.join(format!(
+			".kdeconnect-{}.part",
+			hex::encode(rand::random::<[u8; 8]>())
+		));
+		let mut out = OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.open(&temp)
+			.await?;
+
+		let ret = async {
+			// one byte more than allowed to notice going over
+			let mut data = data.take(limit.map_or(u64::MAX, |x| x.saturating_add(1)));
+			let written = tokio::io::copy(&mut data, &mut out).await?;
+			if limit.is_some_and(|x| written > x) {
+				return Err(KdeConnectError::StorageQuotaExceeded);
+			}
+			out.flush().await?;
+			out.sync_all().await?;
+			out.into_std().await.set_times(file_times(file))?;
+
+			let name = sanitize_file_name(&file.filename);
+			let mut i = 0;
+			loop {
+				let path = directory.join(numbered_file_name(&name, i));
+				if self.move_new(&temp, &path).await? {
+					return Ok((path, written));
+				}
+				i += 1;
+			}
+		}
+		.await;
+
+		if ret.is_err() {
+			let _ = fs::remove_file(&temp).await;
+		}
+		ret
+	}
+
+	// moves temp to path, returns false if path already exists. other programs can create files
+	// in the directory too, so this has to fail instead of replacing them
+	async fn move_new(&self, temp: &Path, path: &Path) -> Result<bool> {
+		match fs::hard_link(temp, path).await {
+			Ok(()) => {
+				if let Err(err) = fs::remove_file(temp).await {
+					warn!("failed to remove {:?}: {:?}", temp, err);
+				}
+				Ok(true)
+			}
+			Err(err) if err.kind() == ErrorKind::AlreadyExists => Ok(false),
+			// filesystems without hard links, only clashes within this process are prevented
+			Err(_) => {
+				let _lock = self.rename_lock.lock().await;
+				if fs::try_exists(path).await? {
+					return Ok(false);
+				}
+				fs::rename(temp, path).await?;
+				Ok(true)
+			}
+		}
+	}
+}
+
+/// Keeps only the last component of a file name sent by a device and replaces characters that
+/// common filesystems don't allow.
+pub fn sanitize_file_name(name: &str) -> String {
+	let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+	let name: String = name
+		.chars()
+		.map(|x| {
+			if x.is_control() || matches!(x, '<' | '>' | ':' | '"' | '|' | '?' | '*') {
+				'_'
+			} else {
+				x
+			}
+		})
+		.collect();
+	// no hidden files, this also gets rid of . and ..
+	let name = name
+		.trim()
+		.trim_start_matches('.')
+		.trim_end_matches(['.', ' ']);
+	if name.is_empty() {
+		return DEFAULT_FILE_NAME.to_string();
+	}
+
+	if name.len() <= MAX_FILE_NAME_LEN {
+		return name.to_string();
+	}
+	let (stem, extension) = match name.rfind('.') {
+		Some(dot) if name.len() - dot <= MAX_EXTENSION_LEN => name.split_at(dot),
+		_ => (name, ""),
+	};
+	let mut len = MAX_FILE_NAME_LEN - extension.len();
+	while !stem.is_char_boundary(len) {
+		len -= 1;
+	}
+	stem[..len].to_string() + extension
+}
+
+// name, name (1), name (2) and so on, before the extension
+fn numbered_file_name(name: &str, i: u64) -> String {
+	if i == 0 {
+		return name.to_string();
+	}
+	match name.rfind('.') {
+		Some(dot) if dot > 0 => format!("{} ({}){}", &name[..dot], i, &name[dot..]),
+		_ => format!("{} ({})", name, i),
+	}
+}
+
+// times from the device are milliseconds since the unix epoch
+fn file_times(file: &ShareRequestFile) -> FileTimes {
+	let to_time = |x: u128| {
+		u64::try_from(x)
+			.ok()
+			.and_then(|x| SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(x)))
+	};
+	let mut times = FileTimes::new();
+	if let Some(modified) = file.last_modified.and_then(to_time) {
+		times = times.set_modified(modified);
+	}
+	// only apple platforms let the creation time be set
+	#[cfg(any(target_os = "macos", target_os = "ios"))]
+	if let Some(created) = file.creation_time.and_then(to_time) {
+		times = times.set_created(created);
+	}
+	times
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn share_file(name: &str) -> ShareRequestFile {
+		ShareRequestFile {
+			filename: name.to_string(),
+			creation_time: None,
+			last_modified: Some(1_700_000_000_000),
+			open: None,
+			number_of_files: None,
+			total_payload_size: None,
+		}
+	}
+
+	#[tokio::test]
+	async fn store_never_replaces() {
+		let dir = std::env::temp_dir().join(format!(
+			"kdeconnect-storage-{}",
+			hex::encode(rand::random::<[u8; 8]>())
+		));
+		fs::create_dir_all(&dir).await.unwrap();
+		fs::write(dir.join("a.txt"), b"existing").await.unwrap();
+		let mut storage = FileStorage::new(dir.clone());
+		storage.set_default_quota(Some(10));
+
+		let path = storage
+			.store("dev", &share_file("../a.txt"), 3, &b"new"[..])
+			.await
+			.unwrap();
+		assert_eq!(
+			path,
+			fs::canonicalize(&dir).await.unwrap().join("a (1).txt")
+		);
+		assert_eq!(fs::read(dir.join("a.txt")).await.unwrap(), b"existing");
+		assert_eq!(fs::read(&path).await.unwrap(), b"new");
+		assert_eq!(storage.usage("dev").await, 3);
+
+		assert!(matches!(
+			storage
+				.store("dev", &share_file("b.txt"), 8, &b"12345678"[..])
+				.await,
+			Err(KdeConnectError::StorageQuotaExceeded)
+		));
+		// only the files that were stored are left
+		let mut names = Vec::new();
+		let mut entries = fs::read_dir(&dir).await.unwrap();
+		while let Some(entry) = entries.next_entry().await.unwrap() {
+			names.push(entry.file_name().into_string().unwrap());
+		}
+		names.sort();
+		assert_eq!(names, ["a (1).txt", "a.txt"]);
+
+		fs::remove_dir_all(dir).await.unwrap();
+	}
+}
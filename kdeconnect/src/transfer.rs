@@ -0,0 +1,332 @@
+use std::{
+	future::{pending, Future},
+	io,
+	net::{IpAddr, SocketAddr},
+	ops::RangeInclusive,
+	pin::Pin,
+	sync::{
+		atomic::{AtomicU16, Ordering},
+		Arc,
+	},
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, Take},
+	net::TcpListener,
+	select,
+	sync::watch,
+	time::{sleep, timeout, Instant, Sleep},
+};
+
+use crate::{KdeConnectError, Result};
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+// how payload transfers are set up, set from KdeConnect
+#[derive(Debug, Clone)]
+pub(crate) struct PayloadConfig {
+	pub(crate) accept_timeout: Duration,
+	pub(crate) idle_timeout: Duration,
+	pub(crate) ports: RangeInclusive<u16>,
+	pub(crate) ephemeral_fallback: bool,
+	// shared by all devices so every payload doesn't rescan the ports that are in use
+	pub(crate) next_port: Arc<AtomicU16>,
+}
+
+impl PayloadConfig {
+	// binds the port after the last one handed out, wrapping around the range once
+	pub(crate) async fn bind(&self, ip: IpAddr) -> Result<(TcpListener, u16)> {
+		let start = *self.ports.start();
+		let len = self.ports.len();
+		let next = self.next_port.load(Ordering::Relaxed);
+		let first = if self.ports.contains(&next) {
+			(next - start) as usize
+		} else {
+			0
+		};
+		for offset in 0..len {
+			let port = start + ((first + offset) % len) as u16;
+			if let Ok(listener) = TcpListener::bind(SocketAddr::new(ip, port)).await {
+				self.next_port
+					.store(port.wrapping_add(1), Ordering::Relaxed);
+				return Ok((listener, port));
+			}
+		}
+		if self.ephemeral_fallback {
+			let listener = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+			let port = listener.local_addr()?.port();
+			return Ok((listener, port));
+		}
+		Err(KdeConnectError::NoPayloadTransferPortFound)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+	pub transferred: u64,
+	// None if the other side didn't say how big the payload is
+	pub total: Option<u64>,
+}
+
+struct TransferState {
+	progress: watch::Sender<TransferProgress>,
+	// shared with the parent, cancelling one file of a share cancels all of them
+	cancelled: Arc<watch::Sender<bool>>,
+	// progress is also added to the parent
+	parent: Option<TransferHandle>,
+}
+
+/// Progress and cancellation of a payload transfer. Clones refer to the same transfer.
+#[derive(Clone)]
+pub struct TransferHandle {
+	state: Arc<TransferState>,
+}
+
+impl TransferHandle {
+	// size is the payload size of the packet, negative if unknown
+	pub fn new(size: i64) -> Self {
+		Self::with_parent(size, Arc::new(watch::channel(false).0), None)
+	}
+
+	// part of this transfer, such as one file of a share
+	pub(crate) fn child(&self, size: i64) -> Self {
+		Self::with_parent(size, self.state.cancelled.clone(), Some(self.clone()))
+	}
+
+	fn with_parent(
+		size: i64,
+		cancelled: Arc<watch::Sender<bool>>,
+		parent: Option<TransferHandle>,
+	) -> Self {
+		let progress = TransferProgress {
+			transferred: 0,
+			total: u64::try_from(size).ok(),
+		};
+		Self {
+			state: Arc::new(TransferState {
+				progress: watch::channel(progress).0,
+				cancelled,
+				parent,
+			}),
+		}
+	}
+
+	pub fn progress(&self) -> TransferProgress {
+		*self.state.progress.borrow()
+	}
+
+	// changes every time a chunk of the payload is transferred
+	pub fn subscribe(&self) -> watch::Receiver<TransferProgress> {
+		self.state.progress.subscribe()
+	}
+
+	// the transfer fails with KdeConnectError::TransferCancelled
+	pub fn cancel(&self) {
+		self.state.cancelled.send_replace(true);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		*self.state.cancelled.borrow()
+	}
+
+	pub(crate) fn cancelled(&self) -> impl Future<Output = ()> + Send + Sync + 'static {
+		let mut cancelled = self.state.cancelled.subscribe();
+		async move {
+			// all handles being dropped doesn't cancel the transfer
+			if cancelled.wait_for(|x| *x).await.is_err() {
+				pending::<()>().await;
+			}
+		}
+	}
+
+	fn add(&self, len: usize) {
+		self.state
+			.progress
+			.send_modify(|x| x.transferred += len as u64);
+		if let Some(parent) = &self.state.parent {
+			parent.add(len);
+		}
+	}
+
+	pub(crate) fn set_total(&self, total: Option<u64>) {
+		self.state.progress.send_modify(|x| x.total = total);
+	}
+
+	// fails if the transfer is cancelled or fut doesn't finish within timeout_after
+	pub(crate) async fn guard<T>(
+		&self,
+		timeout_after: Duration,
+		fut: impl Future<Output = io::Result<T>>,
+	) -> Result<T> {
+		select! {
+			_ = self.cancelled() => Err(KdeConnectError::TransferCancelled),
+			ret = timeout(timeout_after, fut) => {
+				Ok(ret.map_err(|_| KdeConnectError::TransferTimedOut)??)
+			}
+		}
+	}
+}
+
+// like tokio::io::copy but reports progress and fails if either side stalls for idle_timeout
+pub(crate) async fn copy_payload(
+	reader: &mut (impl AsyncRead + Unpin),
+	writer: &mut (impl AsyncWrite + Unpin),
+	transfer: &TransferHandle,
+	idle_timeout: Duration,
+) -> Result<u64> {
+	let mut buf = vec![0; COPY_BUFFER_SIZE];
+	let mut copied = 0;
+	loop {
+		let len = transfer.guard(idle_timeout, reader.read(&mut buf)).await?;
+		if len == 0 {
+			break;
+		}
+		transfer
+			.guard(idle_timeout, writer.write_all(&buf[..len]))
+			.await?;
+		copied += len as u64;
+		transfer.add(len);
+	}
+	transfer.guard(idle_timeout, writer.flush()).await?;
+	Ok(copied)
+}
+
+// payload being recieved, reports progress and fails reads when cancelled or stalled. if the size
+// is known the payload has to be exactly that long
+pub(crate) struct TransferReader<R> {
+	inner: Take<R>,
+	expected: Option<u64>,
+	transfer: TransferHandle,
+	idle_timeout: Duration,
+	idle: Pin<Box<Sleep>>,
+	// the timer only runs while a read is waiting on the device
+	waiting: bool,
+	cancelled: Pin<Box<dyn Future<Output = ()> + Send + Sync>>,
+}
+
+impl<R: AsyncRead + Unpin> TransferReader<R> {
+	pub(crate) fn new(inner: R, transfer: TransferHandle, idle_timeout: Duration) -> Self {
+		let expected = transfer.progress().total;
+		Self {
+			inner: inner.take(expected.unwrap_or(u64::MAX)),
+			expected,
+			idle_timeout,
+			idle: Box::pin(sleep(idle_timeout)),
+			waiting: false,
+			cancelled: Box::pin(transfer.cancelled()),
+			transfer,
+		}
+	}
+
+	fn check_end(&mut self, cx: &mut Context<'_>, expected: u64) -> io::Result<()> {
+		let received = expected - self.inner.limit();
+		if received < expected {
+			return Err(io::Error::new(
+				io::ErrorKind::UnexpectedEof,
+				KdeConnectError::PayloadTruncated(expected, received),
+			));
+		}
+		let mut extra = [0; 1];
+		let mut extra = ReadBuf::new(&mut extra);
+		match Pin::new(self.inner.get_mut()).poll_read(cx, &mut extra) {
+			Poll::Ready(Ok(())) if !extra.filled().is_empty() => Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				KdeConnectError::PayloadOversized(expected),
+			)),
+			// the end of the stream, or kdeconnect-kde closing without a close_notify, or the
+			// device not having closed yet
+			_ => Ok(()),
+		}
+	}
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TransferReader<R> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = &mut *self;
+		if this.cancelled.as_mut().poll(cx).is_ready() {
+			return Poll::Ready(Err(io::Error::other(KdeConnectError::TransferCancelled)));
+		}
+		if !this.waiting {
+			this.idle.as_mut().reset(Instant::now() + this.idle_timeout);
+		}
+
+		let filled = buf.filled().len();
+		match Pin::new(&mut this.inner).poll_read(cx, buf) {
+			Poll::Ready(Ok(())) => {
+				this.waiting = false;
+				let len = buf.filled().len() - filled;
+				this.transfer.add(len);
+				if len == 0
+					&& buf.remaining() > 0
+					&& let Some(expected) = this.expected
+				{
+					return Poll::Ready(this.check_end(cx, expected));
+				}
+				Poll::Ready(Ok(()))
+			}
+			Poll::Ready(Err(err)) => {
+				this.waiting = false;
+				// rustls reports a missing close_notify as UnexpectedEof
+				if err.kind() == io::ErrorKind::UnexpectedEof
+					&& let Some(expected) = this.expected
+				{
+					return Poll::Ready(this.check_end(cx, expected));
+				}
+				Poll::Ready(Err(err))
+			}
+			Poll::Pending => {
+				this.waiting = true;
+				if this.idle.as_mut().poll(cx).is_ready() {
+					this.waiting = false;
+					Poll::Ready(Err(io::Error::new(
+						io::ErrorKind::TimedOut,
+						KdeConnectError::TransferTimedOut,
+					)))
+				} else {
+					Poll::Pending
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::sink;
+
+	use super::*;
+
+	async fn read_all(data: &[u8], transfer: TransferHandle) -> Result<u64> {
+		let mut reader = TransferReader::new(data, transfer, Duration::from_secs(5));
+		Ok(tokio::io::copy(&mut reader, &mut sink()).await?)
+	}
+
+	#[tokio::test]
+	async fn payload_errors_keep_their_type() {
+		let transfer = TransferHandle::new(5);
+		assert_eq!(read_all(b"12345", transfer.clone()).await.unwrap(), 5);
+		assert_eq!(transfer.progress().transferred, 5);
+
+		assert!(matches!(
+			read_all(b"12345", TransferHandle::new(10)).await,
+			Err(KdeConnectError::PayloadTruncated(10, 5))
+		));
+		assert!(matches!(
+			read_all(b"12345", TransferHandle::new(3)).await,
+			Err(KdeConnectError::PayloadOversized(3))
+		));
+
+		let transfer = TransferHandle::new(5);
+		transfer.cancel();
+		assert!(matches!(
+			read_all(b"12345", transfer).await,
+			Err(KdeConnectError::TransferCancelled)
+		));
+	}
+}
@@ -1,32 +1,40 @@
 use std::{
 	future::Future,
-	net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+	io::{self, ErrorKind},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 	pin::Pin,
 	sync::Arc,
 	time::Duration,
 };
 
-use log::info;
+use log::{info, warn};
 use rcgen::{Certificate, CertificateParams, DnType, KeyPair};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
 use time::OffsetDateTime;
 use tokio::{
-	io::{AsyncRead, AsyncWriteExt},
-	net::{TcpListener, TcpStream},
+	io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream, UdpSocket},
 };
 use tokio_rustls::{
 	rustls::{
 		self,
 		client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
-		crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
-		pki_types::{CertificateDer, ServerName, UnixTime},
+		crypto::{
+			ring::default_provider, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+		},
+		pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
 		server::danger::{ClientCertVerified, ClientCertVerifier},
-		ClientConfig, DigitallySignedStruct, ServerConfig,
+		CertificateError, ClientConfig, DigitallySignedStruct, ServerConfig,
 	},
 	TlsAcceptor, TlsConnector,
 };
 use x509_parser::{certificate::X509Certificate, der_parser::asn1_rs::FromDer};
 
-use crate::{packets::PacketPayloadTransferInfo, KdeConnectError};
+use crate::{
+	packets::PacketPayloadTransferInfo,
+	transfer::{copy_payload, PayloadConfig, TransferHandle, TransferReader},
+	KdeConnectError, LinkKeepalive,
+};
 
 pub(crate) fn generate_server_cert(
 	keypair: &KeyPair,
@@ -51,25 +59,83 @@ pub(crate) fn get_public_key(cert: &[u8]) -> Result<Vec<u8>, KdeConnectError> {
 	Ok(X509Certificate::from_der(cert)?.1.public_key().raw.to_vec())
 }
 
+#[derive(Clone)]
+pub(crate) struct TlsIdentity {
+	cert: CertificateDer<'static>,
+	keypair: Vec<u8>,
+}
+
+impl TlsIdentity {
+	pub fn new(cert: CertificateDer<'static>, keypair: Vec<u8>) -> Self {
+		Self { cert, keypair }
+	}
+
+	fn key(&self) -> PrivateKeyDer<'static> {
+		PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.keypair.clone()))
+	}
+
+	// if expected is None any certificate is accepted, this is used for unpaired devices
+	pub fn server_config(
+		&self,
+		expected: Option<Vec<u8>>,
+	) -> Result<Arc<ServerConfig>, KdeConnectError> {
+		let verifier = Arc::new(PinnedCertificateVerifier::new(default_provider(), expected));
+		Ok(Arc::new(
+			ServerConfig::builder()
+				.with_client_cert_verifier(verifier)
+				.with_single_cert(vec![self.cert.clone()], self.key())?,
+		))
+	}
+
+	pub fn client_config(
+		&self,
+		expected: Option<Vec<u8>>,
+	) -> Result<Arc<ClientConfig>, KdeConnectError> {
+		let verifier = Arc::new(PinnedCertificateVerifier::new(default_provider(), expected));
+		Ok(Arc::new(
+			ClientConfig::builder()
+				.dangerous()
+				.with_custom_certificate_verifier(verifier)
+				.with_client_auth_cert(vec![self.cert.clone()], self.key())?,
+		))
+	}
+}
+
 #[derive(Debug)]
-pub(crate) struct NoCertificateVerification(CryptoProvider);
+pub(crate) struct PinnedCertificateVerifier {
+	provider: CryptoProvider,
+	expected: Option<Vec<u8>>,
+}
+
+impl PinnedCertificateVerifier {
+	pub fn new(provider: CryptoProvider, expected: Option<Vec<u8>>) -> Self {
+		Self { provider, expected }
+	}
 
-impl NoCertificateVerification {
-	pub fn new(provider: CryptoProvider) -> Self {
-		Self(provider)
+	fn verify_cert(&self, cert: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+		match self.expected {
+			Some(ref expected) if expected.as_slice() != cert.as_ref() => {
+				warn!("peer presented a certificate that does not match the paired certificate");
+				Err(rustls::Error::InvalidCertificate(
+					CertificateError::ApplicationVerificationFailure,
+				))
+			}
+			_ => Ok(()),
+		}
 	}
 }
 
-impl ServerCertVerifier for NoCertificateVerification {
+impl ServerCertVerifier for PinnedCertificateVerifier {
 	fn verify_server_cert(
 		&self,
-		_end_entity: &CertificateDer<'_>,
+		end_entity: &CertificateDer<'_>,
 		_intermediates: &[CertificateDer<'_>],
 		_server_name: &ServerName<'_>,
 		_ocsp: &[u8],
 		_now: UnixTime,
 	) -> Result<ServerCertVerified, rustls::Error> {
-		Ok(rustls::client::danger::ServerCertVerified::assertion())
+		self.verify_cert(end_entity)?;
+		Ok(ServerCertVerified::assertion())
 	}
 
 	fn verify_tls12_signature(
@@ -82,7 +148,7 @@ impl ServerCertVerifier for NoCertificateVerification {
 			message,
 			cert,
 			dss,
-			&self.0.signature_verification_algorithms,
+			&self.provider.signature_verification_algorithms,
 		)
 	}
 
@@ -96,22 +162,26 @@ impl ServerCertVerifier for NoCertificateVerification {
 			message,
 			cert,
 			dss,
-			&self.0.signature_verification_algorithms,
+			&self.provider.signature_verification_algorithms,
 		)
 	}
 
 	fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-		self.0.signature_verification_algorithms.supported_schemes()
+		self.provider
+			.signature_verification_algorithms
+			.supported_schemes()
 	}
 }
 
-impl ClientCertVerifier for NoCertificateVerification {
+impl ClientCertVerifier for PinnedCertificateVerifier {
 	fn root_hint_subjects(&self) -> &[rustls::DistinguishedName] {
 		&[]
 	}
 
 	fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-		self.0.signature_verification_algorithms.supported_schemes()
+		self.provider
+			.signature_verification_algorithms
+			.supported_schemes()
 	}
 
 	fn verify_tls12_signature(
@@ -124,7 +194,7 @@ impl ClientCertVerifier for NoCertificateVerification {
 			message,
 			cert,
 			dss,
-			&self.0.signature_verification_algorithms,
+			&self.provider.signature_verification_algorithms,
 		)
 	}
 
@@ -138,16 +208,17 @@ impl ClientCertVerifier for NoCertificateVerification {
 			message,
 			cert,
 			dss,
-			&self.0.signature_verification_algorithms,
+			&self.provider.signature_verification_algorithms,
 		)
 	}
 
 	fn verify_client_cert(
 		&self,
-		_end_entity: &CertificateDer<'_>,
+		end_entity: &CertificateDer<'_>,
 		_intermediates: &[CertificateDer<'_>],
 		_now: UnixTime,
 	) -> Result<ClientCertVerified, rustls::Error> {
+		self.verify_cert(end_entity)?;
 		Ok(ClientCertVerified::assertion())
 	}
 }
@@ -159,48 +230,212 @@ pub(crate) fn get_time_ms() -> u128 {
 		.as_millis()
 }
 
-pub(crate) async fn create_payload(
-	payload: impl AsyncRead + Sync + Send + Unpin,
-	server_config: Arc<ServerConfig>,
-) -> Result<(u16, impl Future<Output = ()> + Sync + Send), KdeConnectError> {
-	let mut free_listener: Option<TcpListener> = None;
-	let mut free_port: Option<u16> = None;
-	for port in 60000..=64000 {
-		if let Ok(listener) =
-			TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).await
-		{
-			free_listener = Some(listener);
-			free_port = Some(port);
+pub(crate) fn get_time_secs() -> u64 {
+	std::time::SystemTime::now()
+		.duration_since(std::time::SystemTime::UNIX_EPOCH)
+		.expect("time went backwards")
+		.as_secs()
+}
+
+// reads a single line without buffering past it, so the stream can be handed off afterwards
+pub(crate) async fn read_line_unbuffered(
+	stream: &mut (impl AsyncRead + Unpin),
+	max_len: usize,
+) -> Result<String, KdeConnectError> {
+	let mut buf = Vec::new();
+	loop {
+		let byte = stream.read_u8().await?;
+		if byte == b'\n' {
 			break;
 		}
+		if buf.len() == max_len {
+			return Err(KdeConnectError::LineTooLong(max_len));
+		}
+		buf.push(byte);
 	}
-	if let Some(free_listener) = free_listener
-		&& let Some(free_port) = free_port
-	{
-		Ok((free_port, async move {
-			let mut payload = payload;
-			if let Ok(incoming) = free_listener.accept().await
-				&& let Ok(mut stream) = TlsAcceptor::from(server_config).accept(incoming.0).await
-				&& let Ok(_) = tokio::io::copy(&mut payload, &mut stream).await
-				&& let Ok(_) = stream.flush().await
-			{
-				let _ = stream.shutdown().await;
+	String::from_utf8(buf).map_err(|x| std::io::Error::new(ErrorKind::InvalidData, x).into())
+}
+
+// like tokio's Lines but lines longer than max_len are an error, also cancel safe
+pub(crate) struct LimitedLines<R> {
+	reader: R,
+	buf: Vec<u8>,
+	max_len: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> LimitedLines<R> {
+	pub(crate) fn new(reader: R, max_len: usize) -> Self {
+		Self {
+			reader,
+			buf: Vec::new(),
+			max_len,
+		}
+	}
+
+	pub(crate) async fn next_line(&mut self) -> Result<Option<String>, KdeConnectError> {
+		loop {
+			let available = self.reader.fill_buf().await?;
+			if available.is_empty() {
+				if self.buf.is_empty() {
+					return Ok(None);
+				}
+				// the last line doesn't need a newline
+				return self.take_line().map(Some);
+			}
+
+			let newline = available.iter().position(|x| *x == b'\n');
+			let len = newline.unwrap_or(available.len());
+			if self.buf.len() + len > self.max_len {
+				return Err(KdeConnectError::LineTooLong(self.max_len));
+			}
+			self.buf.extend_from_slice(&available[..len]);
+			// progress is kept in buf so the future can be dropped at any await
+			self.reader.consume(newline.map_or(len, |x| x + 1));
+
+			if newline.is_some() {
+				return self.take_line().map(Some);
 			}
-			info!("successfully sent payload on port {}", free_port)
-		}))
-	} else {
-		Err(KdeConnectError::NoPayloadTransferPortFound)
+		}
 	}
+
+	fn take_line(&mut self) -> Result<String, KdeConnectError> {
+		if self.buf.last() == Some(&b'\r') {
+			self.buf.pop();
+		}
+		String::from_utf8(std::mem::take(&mut self.buf))
+			.map_err(|x| io::Error::new(ErrorKind::InvalidData, x).into())
+	}
+}
+
+// v6 sockets are made v6 only unless asked otherwise, so a v4 socket can share the port
+fn new_v6_socket(ty: Type, protocol: Protocol, port: u16, only_v6: bool) -> io::Result<Socket> {
+	let socket = Socket::new(Domain::IPV6, ty, Some(protocol))?;
+	socket.set_only_v6(only_v6)?;
+	socket.set_nonblocking(true)?;
+	if ty == Type::STREAM {
+		socket.set_reuse_address(true)?;
+	}
+	socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+	Ok(socket)
+}
+
+pub(crate) fn bind_tcp_v6(port: u16, only_v6: bool) -> io::Result<TcpListener> {
+	let socket = new_v6_socket(Type::STREAM, Protocol::TCP, port, only_v6)?;
+	socket.listen(1024)?;
+	TcpListener::from_std(socket.into())
 }
 
+pub(crate) fn bind_udp_v6(port: u16) -> io::Result<UdpSocket> {
+	UdpSocket::from_std(new_v6_socket(Type::DGRAM, Protocol::UDP, port, true)?.into())
+}
+
+pub(crate) fn set_keepalive(stream: &TcpStream, keepalive: &LinkKeepalive) -> io::Result<()> {
+	let socket = SockRef::from(stream);
+	let params = TcpKeepalive::new().with_time(keepalive.idle);
+	#[cfg(any(
+		target_os = "android",
+		target_os = "ios",
+		target_os = "linux",
+		target_os = "macos"
+	))]
+	let params = params
+		.with_interval(keepalive.interval)
+		.with_retries(keepalive.retries);
+	socket.set_tcp_keepalive(&params)?;
+	// keepalive probes aren't sent while data is unacknowledged
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	socket.set_tcp_user_timeout(Some(
+		keepalive.idle + keepalive.interval * keepalive.retries,
+	))?;
+	Ok(())
+}
+
+// listens on the address family of local_ip so the device can reach it the same way as the link
+// the returned future sends the payload once the device connects and resolves to whether it
+// was sent completely
+pub(crate) async fn create_payload(
+	payload: impl AsyncRead + Sync + Send + Unpin,
+	local_ip: IpAddr,
+	server_config: Arc<ServerConfig>,
+	payload_config: PayloadConfig,
+	transfer: TransferHandle,
+) -> Result<
+	(
+		u16,
+		impl Future<Output = Result<(), KdeConnectError>> + Sync + Send,
+	),
+	KdeConnectError,
+> {
+	let unspecified: IpAddr = match local_ip {
+		IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+		IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+	};
+	let (free_listener, free_port) = payload_config.bind(unspecified).await?;
+	Ok((free_port, async move {
+		let mut payload = payload;
+		let ret = async {
+			let (incoming, _) = transfer
+				.guard(payload_config.accept_timeout, free_listener.accept())
+				.await?;
+			// only one connection is accepted
+			drop(free_listener);
+			let mut stream = transfer
+				.guard(
+					payload_config.idle_timeout,
+					TlsAcceptor::from(server_config).accept(incoming),
+				)
+				.await?;
+			let sent = copy_payload(
+				&mut payload,
+				&mut stream,
+				&transfer,
+				payload_config.idle_timeout,
+			)
+			.await?;
+			// peers sometimes close before close_notify is sent, the payload is already there
+			let _ = transfer
+				.guard(payload_config.idle_timeout, stream.shutdown())
+				.await;
+			Ok(sent)
+		}
+		.await;
+		match ret {
+			Ok(sent) => {
+				info!(
+					"successfully sent payload of {} bytes on port {}",
+					sent, free_port
+				);
+				Ok(())
+			}
+			Err(err) => {
+				warn!("failed to send payload on port {}: {:?}", free_port, err);
+				Err(err)
+			}
+		}
+	}))
+}
+
+// addr is the address of the link, link local v6 addresses need its scope id
 pub(crate) async fn get_payload(
-	ip: IpAddr,
+	mut addr: SocketAddr,
 	transfer_info: PacketPayloadTransferInfo,
 	client_config: Arc<ClientConfig>,
+	payload_config: PayloadConfig,
+	transfer: TransferHandle,
 ) -> Result<Pin<Box<dyn AsyncRead + Sync + Send>>, KdeConnectError> {
-	let stream = TcpStream::connect(SocketAddr::new(ip, transfer_info.port)).await?;
-	let tls = TlsConnector::from(client_config)
-		.connect("kdeconnectjb".try_into()?, stream)
+	addr.set_port(transfer_info.port);
+	let stream = transfer
+		.guard(payload_config.accept_timeout, TcpStream::connect(addr))
+		.await?;
+	let tls = transfer
+		.guard(
+			payload_config.idle_timeout,
+			TlsConnector::from(client_config).connect("kdeconnectjb".try_into()?, stream),
+		)
 		.await?;
-	Ok(Box::pin(tls))
+	Ok(Box::pin(TransferReader::new(
+		tls,
+		transfer,
+		payload_config.idle_timeout,
+	)))
 }
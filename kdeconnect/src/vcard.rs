@@ -0,0 +1,439 @@
+use std::fmt::Display;
+
+use crate::{KdeConnectError, Result};
+
+// vcard lines should be folded at 75 octets
+const MAX_LINE_LENGTH: usize = 75;
+
+#[derive(Clone, Debug, Default)]
+pub struct VCard {
+	pub properties: Vec<VCardProperty>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VCardProperty {
+	pub group: Option<String>,
+	pub name: String,
+	pub params: Vec<(String, String)>,
+	// raw value, use text() for unescaped text values
+	pub value: String,
+}
+
+impl VCardProperty {
+	pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+		Self {
+			group: None,
+			name: name.into(),
+			params: Vec::new(),
+			value: value.into(),
+		}
+	}
+
+	pub fn new_text(name: impl Into<String>, text: &str) -> Self {
+		Self::new(name, escape_text(text))
+	}
+
+	pub fn text(&self) -> String {
+		unescape_text(&self.value)
+	}
+
+	pub fn param(&self, key: &str) -> Option<&str> {
+		self.params
+			.iter()
+			.find(|x| x.0.eq_ignore_ascii_case(key))
+			.map(|x| x.1.as_str())
+	}
+
+	fn parse(line: &str) -> Result<Self> {
+		let colon = find_unquoted(line, ':').ok_or(KdeConnectError::InvalidVCard)?;
+		let (key, value) = (&line[..colon], &line[colon + 1..]);
+
+		let mut parts = split_unquoted(key, ';').into_iter();
+		let name = parts.next().ok_or(KdeConnectError::InvalidVCard)?;
+		let (group, name) = match name.split_once('.') {
+			Some((group, name)) => (Some(group.to_string()), name),
+			None => (None, name),
+		};
+		if name.is_empty() {
+			return Err(KdeConnectError::InvalidVCard);
+		}
+
+		let params = parts
+			.map(|param| match param.split_once('=') {
+				Some((key, value)) => (key.to_uppercase(), value.trim_matches('"').to_string()),
+				// vcard 2.1 allows bare types like TEL;CELL:... and bare encodings
+				None if is_encoding(param) => ("ENCODING".to_string(), param.to_string()),
+				None => ("TYPE".to_string(), param.to_string()),
+			})
+			.collect();
+
+		let mut property = Self {
+			group,
+			name: name.to_string(),
+			params,
+			value: value.to_string(),
+		};
+		if property
+			.param("ENCODING")
+			.is_some_and(|x| x.eq_ignore_ascii_case("QUOTED-PRINTABLE"))
+		{
+			property.value = escape_decoded(&decode_quoted_printable(
+				&property.value,
+				property.param("CHARSET"),
+			));
+			// the value is utf-8 now, which is also how it's written back
+			property
+				.params
+				.retain(|x| x.0 != "ENCODING" && x.0 != "CHARSET");
+		}
+		Ok(property)
+	}
+}
+
+impl Display for VCardProperty {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let mut line = String::new();
+		if let Some(group) = self.group.as_ref() {
+			line.push_str(group);
+			line.push('.');
+		}
+		line.push_str(&self.name);
+		for (key, value) in self.params.iter() {
+			line.push(';');
+			line.push_str(key);
+			line.push('=');
+			if value.contains([':', ';', ',']) {
+				line.push('"');
+				line.push_str(value);
+				line.push('"');
+			} else {
+				line.push_str(value);
+			}
+		}
+		line.push(':');
+		line.push_str(&self.value);
+		write_folded(f, &line)
+	}
+}
+
+impl VCard {
+	/// Parses all vCards in `text`.
+	pub fn parse_all(text: &str) -> Result<Vec<Self>> {
+		let mut cards = Vec::new();
+		let mut current: Option<VCard> = None;
+
+		for line in unfold(text) {
+			if line.trim().is_empty() {
+				continue;
+			}
+			let property = VCardProperty::parse(&line)?;
+			match (property.name.to_uppercase().as_str(), current.as_mut()) {
+				("BEGIN", None) if property.value.eq_ignore_ascii_case("VCARD") => {
+					current = Some(VCard::default());
+				}
+				("END", Some(_)) if property.value.eq_ignore_ascii_case("VCARD") => {
+					// we know that it's some because of the match
+					cards.push(current.take().unwrap());
+				}
+				("BEGIN", Some(_)) | ("END", _) | (_, None) => {
+					return Err(KdeConnectError::InvalidVCard);
+				}
+				(_, Some(card)) => card.properties.push(property),
+			}
+		}
+
+		if current.is_some() {
+			return Err(KdeConnectError::InvalidVCard);
+		}
+		Ok(cards)
+	}
+
+	/// Parses a single vCard.
+	pub fn parse(text: &str) -> Result<Self> {
+		let mut cards = Self::parse_all(text)?;
+		if cards.len() != 1 {
+			return Err(KdeConnectError::InvalidVCard);
+		}
+		Ok(cards.remove(0))
+	}
+
+	pub fn get(&self, name: &str) -> Option<&VCardProperty> {
+		self.properties
+			.iter()
+			.find(|x| x.name.eq_ignore_ascii_case(name))
+	}
+
+	pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a VCardProperty> {
+		self.properties
+			.iter()
+			.filter(move |x| x.name.eq_ignore_ascii_case(name))
+	}
+
+	pub fn uid(&self) -> Option<String> {
+		self.get("UID").map(|x| x.text())
+	}
+
+	pub fn formatted_name(&self) -> Option<String> {
+		self.get("FN").map(|x| x.text())
+	}
+
+	pub fn phone_numbers(&self) -> Vec<String> {
+		self.get_all("TEL").map(|x| x.text()).collect()
+	}
+
+	pub fn emails(&self) -> Vec<String> {
+		self.get_all("EMAIL").map(|x| x.text()).collect()
+	}
+}
+
+impl Display for VCard {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "BEGIN:VCARD\r\n")?;
+		for property in self.properties.iter() {
+			write!(f, "{}", property)?;
+		}
+		write!(f, "END:VCARD\r\n")
+	}
+}
+
+fn unfold(text: &str) -> Vec<String> {
+	let mut lines: Vec<String> = Vec::new();
+	// quoted printable values ending in = continue on the next line as is
+	let mut soft_break = false;
+	for line in text.lines() {
+		let line = line.strip_suffix('\r').unwrap_or(line);
+		if soft_break && let Some(last) = lines.last_mut() {
+			last.pop();
+			last.push_str(line);
+		} else if let Some(continuation) = line.strip_prefix([' ', '\t'])
+			&& let Some(last) = lines.last_mut()
+		{
+			last.push_str(continuation);
+		} else {
+			lines.push(line.to_string());
+		}
+		soft_break = lines
+			.last()
+			.is_some_and(|x| x.ends_with('=') && is_quoted_printable(x));
+	}
+	lines
+}
+
+fn is_encoding(param: &str) -> bool {
+	["QUOTED-PRINTABLE", "BASE64", "8BIT", "7BIT"]
+		.iter()
+		.any(|x| param.eq_ignore_ascii_case(x))
+}
+
+fn is_quoted_printable(line: &str) -> bool {
+	find_unquoted(line, ':').is_some_and(|colon| {
+		line[..colon]
+			.to_ascii_uppercase()
+			.contains("QUOTED-PRINTABLE")
+	})
+}
+
+// soft line breaks have already been removed by unfold
+fn decode_quoted_printable(value: &str, charset: Option<&str>) -> String {
+	let mut bytes = Vec::with_capacity(value.len());
+	let mut rest = value.as_bytes();
+	while let Some((&byte, tail)) = rest.split_first() {
+		if byte == b'='
+			&& let Some(hex) = tail.get(..2)
+			&& let Ok(decoded) = hex::decode(hex)
+		{
+			bytes.extend(decoded);
+			rest = &tail[2..];
+		} else {
+			bytes.push(byte);
+			rest = tail;
+		}
+	}
+	match charset {
+		Some(x) if x.eq_ignore_ascii_case("ISO-8859-1") || x.eq_ignore_ascii_case("LATIN1") => {
+			bytes.into_iter().map(char::from).collect()
+		}
+		// kdeconnect-android always uses utf-8
+		_ => String::from_utf8_lossy(&bytes).into_owned(),
+	}
+}
+
+fn write_folded(f: &mut std::fmt::Formatter<'_>, line: &str) -> std::fmt::Result {
+	let mut len = 0;
+	for char in line.chars() {
+		// continuation lines start with a space which counts towards the limit
+		if len + char.len_utf8() > MAX_LINE_LENGTH {
+			write!(f, "\r\n ")?;
+			len = 1;
+		}
+		write!(f, "{}", char)?;
+		len += char.len_utf8();
+	}
+	write!(f, "\r\n")
+}
+
+fn find_unquoted(text: &str, delimiter: char) -> Option<usize> {
+	let mut quoted = false;
+	for (i, char) in text.char_indices() {
+		match char {
+			'"' => quoted = !quoted,
+			x if x == delimiter && !quoted => return Some(i),
+			_ => {}
+		}
+	}
+	None
+}
+
+fn split_unquoted(text: &str, delimiter: char) -> Vec<&str> {
+	let mut out = Vec::new();
+	let mut rest = text;
+	while let Some(i) = find_unquoted(rest, delimiter) {
+		out.push(&rest[..i]);
+		rest = &rest[i + delimiter.len_utf8()..];
+	}
+	out.push(rest);
+	out
+}
+
+pub fn escape_text(text: &str) -> String {
+	let mut out = String::with_capacity(text.len());
+	for char in text.chars() {
+		match char {
+			'\\' => out.push_str("\\\\"),
+			',' => out.push_str("\\,"),
+			';' => out.push_str("\\;"),
+			'\n' => out.push_str("\\n"),
+			'\r' => {}
+			x => out.push(x),
+		}
+	}
+	out
+}
+
+// vcard 2.1 only escapes ; which also separates the parts of structured values, everything else
+// is escaped like a 3.0 value so text() and writing the card back see the same value
+fn escape_decoded(text: &str) -> String {
+	let mut out = String::with_capacity(text.len());
+	let mut chars = text.chars().peekable();
+	while let Some(char) = chars.next() {
+		match char {
+			'\\' if chars.next_if_eq(&';').is_some() => out.push_str("\\;"),
+			'\\' => out.push_str("\\\\"),
+			',' => out.push_str("\\,"),
+			'\r' if chars.peek() == Some(&'\n') => {}
+			'\r' | '\n' => out.push_str("\\n"),
+			x => out.push(x),
+		}
+	}
+	out
+}
+
+pub fn unescape_text(text: &str) -> String {
+	let mut out = String::with_capacity(text.len());
+	let mut chars = text.chars();
+	while let Some(char) = chars.next() {
+		if char == '\\' {
+			match chars.next() {
+				Some('n') | Some('N') => out.push('\n'),
+				Some(x) => out.push(x),
+				None => out.push('\\'),
+			}
+		} else {
+			out.push(char);
+		}
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// how kdeconnect-android exports contacts with non ascii names
+	const VCARD_21: &str = "BEGIN:VCARD\r\n\
+		VERSION:2.1\r\n\
+		N;CHARSET=UTF-8;ENCODING=QUOTED-PRINTABLE:M=C3=BCller;J=C3=BCrgen;;;\r\n\
+		FN;CHARSET=UTF-8;ENCODING=QUOTED-PRINTABLE:J=C3=BCrgen M=C3=BCller =E6=97=A5=E6=9C=AC=E8=AA=9E =\r\n\
+		=E3=81=AE=E5=90=8D=E5=89=8D\r\n\
+		TEL;CELL:+49 170 1234567\r\n\
+		NOTE;QUOTED-PRINTABLE;CHARSET=ISO-8859-1:Gr=FC=DFe=0D=0A=\r\n\
+		zweite Zeile\r\n\
+		X-KDECONNECT-ID-DEV-test:42\r\n\
+		END:VCARD\r\n";
+
+	#[test]
+	fn parse_quoted_printable() {
+		let card = VCard::parse(VCARD_21).unwrap();
+		assert_eq!(
+			card.formatted_name().unwrap(),
+			"Jürgen Müller 日本語 の名前"
+		);
+		assert_eq!(card.get("N").unwrap().value, "Müller;Jürgen;;;");
+		assert_eq!(card.get("NOTE").unwrap().text(), "Grüße\nzweite Zeile");
+		assert_eq!(card.phone_numbers(), ["+49 170 1234567"]);
+		assert_eq!(card.get("TEL").unwrap().param("TYPE"), Some("CELL"));
+		// decoded values don't keep their encoding
+		assert!(card.get("FN").unwrap().params.is_empty());
+		assert!(card.get("NOTE").unwrap().params.is_empty());
+	}
+
+	#[test]
+	fn quoted_printable_round_trip() {
+		let card = VCard::parse(
+			"BEGIN:VCARD\r\n\
+			VERSION:2.1\r\n\
+			NOTE;ENCODING=QUOTED-PRINTABLE:C:=5Cdir=5C, a=5C;b=0D=0Ac\r\n\
+			END:VCARD\r\n",
+		)
+		.unwrap();
+		let note = card.get("NOTE").unwrap();
+		assert_eq!(note.text(), "C:\\dir\\, a;b\nc");
+
+		let parsed = VCard::parse(&card.to_string()).unwrap();
+		assert_eq!(parsed.get("NOTE").unwrap().value, note.value);
+		assert_eq!(parsed.get("NOTE").unwrap().text(), note.text());
+	}
+
+	#[test]
+	fn parse_folded() {
+		let card = VCard::parse(
+			"BEGIN:VCARD\nVERSION:3.0\nFN:Some\n  Name\nEMAIL;TYPE=\"home,pref\":a@b.c\nEND:VCARD\n",
+		)
+		.unwrap();
+		assert_eq!(card.formatted_name().unwrap(), "Some Name");
+		assert_eq!(card.emails(), ["a@b.c"]);
+		assert_eq!(card.get("EMAIL").unwrap().param("type"), Some("home,pref"));
+	}
+
+	#[test]
+	fn parse_invalid() {
+		assert!(VCard::parse("BEGIN:VCARD\r\nFN:a\r\n").is_err());
+		assert!(VCard::parse("BEGIN:VCARD\r\nnot a property\r\nEND:VCARD\r\n").is_err());
+		assert!(VCard::parse("FN:a\r\n").is_err());
+	}
+
+	#[test]
+	fn serialize() {
+		let mut card = VCard::parse(VCARD_21).unwrap();
+		card.properties
+			.push(VCardProperty::new_text("NOTE", &"a, b; c\n".repeat(20)));
+		let text = card.to_string();
+
+		assert!(text.starts_with("BEGIN:VCARD\r\nVERSION:2.1\r\n"));
+		assert!(text.contains("\r\nFN:Jürgen Müller 日本語 の名前\r\n"));
+		assert!(!text.contains("QUOTED-PRINTABLE"));
+		assert!(text.lines().all(|x| x.len() <= MAX_LINE_LENGTH + 1));
+
+		let parsed = VCard::parse(&text).unwrap();
+		assert_eq!(parsed.properties.len(), card.properties.len());
+		for (a, b) in parsed.properties.iter().zip(card.properties.iter()) {
+			assert_eq!(a.name, b.name);
+			assert_eq!(a.params, b.params);
+			assert_eq!(a.value, b.value);
+		}
+		assert_eq!(
+			parsed.get_all("NOTE").last().unwrap().text(),
+			"a, b; c\n".repeat(20)
+		);
+	}
+}
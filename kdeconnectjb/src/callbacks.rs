@@ -24,6 +24,7 @@ pub struct KConnectCallbacks {
 	pub commands_changed: Option<Arc<dyn Fn(char_p::Box) + Sync + Send>>,
 
 	pub open_file: Option<Arc<dyn Fn(char_p::Box) + Sync + Send>>,
+	pub files_shared: Option<Arc<dyn Fn(char_p::Box, char_p::Box) + Sync + Send>>,
 	pub open_url: Option<Arc<dyn Fn(char_p::Box) + Sync + Send>>,
 	pub open_text: Option<Arc<dyn Fn(char_p::Box) + Sync + Send>>,
 }
@@ -51,6 +52,7 @@ impl KConnectCallbacks {
 			player_change_requested: None,
 
 			open_file: None,
+			files_shared: None,
 			open_url: None,
 			open_text: None,
 		}
@@ -196,6 +198,15 @@ callback!(
 	x
 );
 
+// device id and the saved paths separated by newlines, for shares of more than one file
+callback!(
+	kdeconnect_register_files_shared_callback,
+	extern "C" fn(char_p::Box, char_p::Box) -> (),
+	files_shared,
+	x,
+	y
+);
+
 callback!(
 	kdeconnect_register_open_url_callback,
 	extern "C" fn(char_p::Box) -> (),
@@ -1,25 +1,34 @@
 #![allow(clippy::size_of_in_element_count)]
 
-use std::{
-	collections::HashMap,
-	error::Error,
-	ffi::OsStr,
-	path::PathBuf,
-	pin::Pin,
-	sync::Arc,
-	time::{Duration, SystemTime},
-};
+use std::{collections::HashMap, path::PathBuf, pin::Pin, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use kdeconnect::{
+	commands::CommandOutput,
 	device::{DeviceClient, DeviceConfig, DeviceHandler},
 	packets::{
 		Battery, ConnectivityReport, DeviceType, MousepadEcho, MousepadKeyboardState,
-		MousepadRequest, MprisAction, MprisLoopStatus, MprisPlayer, MprisRequestAction, Ping,
-		Presenter, RunCommandItem, ShareRequestFile, ShareRequestUpdate, SystemVolume,
+		MousepadRequest, MprisAction, MprisLoopStatus, MprisPlayer, MprisRequestAction, Packet,
+		Ping, Presenter, RunCommandItem, ShareRequestFile, ShareRequestUpdate, SystemVolume,
 		SystemVolumeRequest, SystemVolumeStream, Telephony, TelephonyEvent,
 	},
+	plugins::{
+		battery::BatteryHandler,
+		clipboard::ClipboardHandler,
+		connectivity_report::ConnectivityReportHandler,
+		findmyphone::FindPhoneHandler,
+		mousepad::MousepadHandler,
+		mpris::MprisHandler,
+		ping::PingHandler,
+		presenter::PresenterHandler,
+		runcommand::RunCommandHandler,
+		share::{ShareHandler, ShareSession},
+		systemvolume::SystemVolumeHandler,
+		telephony::TelephonyHandler,
+	},
+	storage::FileStorage,
+	transfer::TransferHandle,
 	KdeConnectError,
 };
 use log::{error, info, warn};
@@ -34,7 +43,10 @@ use tokio::{
 use tokio_stream::StreamExt;
 use tokio_util::io::StreamReader;
 
-use crate::{call_callback, call_callback_no_ret, STATE};
+use crate::{call_callback, call_callback_no_ret, STATE, UNKNOWN_BATTERY};
+
+// player info, album art path, position update task
+pub type KConnectPlayerState = (MprisPlayer, Option<String>, Option<JoinHandle<()>>);
 
 #[derive(Default)]
 pub struct KConnectDeviceState {
@@ -42,7 +54,7 @@ pub struct KConnectDeviceState {
 	pub clipboard: Option<String>,
 	pub connectivity: Option<ConnectivityReport>,
 	pub systemvolume: Option<Vec<SystemVolumeStream>>,
-	pub players: HashMap<String, (MprisPlayer, Option<String>, Option<JoinHandle<()>>)>,
+	pub players: HashMap<String, KConnectPlayerState>,
 	pub commands: HashMap<String, RunCommandItem>,
 }
 
@@ -52,6 +64,7 @@ pub struct KConnectDevice {
 	pub state: Arc<Mutex<KConnectDeviceState>>,
 }
 
+#[derive(Clone)]
 pub struct KConnectHandler {
 	state: Arc<Mutex<KConnectDeviceState>>,
 	client: Arc<DeviceClient>,
@@ -59,6 +72,9 @@ pub struct KConnectHandler {
 	id: char_p::Box,
 	verification_key: char_p::Box,
 	documents_path: PathBuf,
+	storage: Arc<FileStorage>,
+	// saved files of the share session that is running
+	shared_files: Vec<String>,
 }
 
 impl KConnectHandler {
@@ -68,6 +84,7 @@ impl KConnectHandler {
 		mut config: DeviceConfig,
 		verification_key: String,
 		documents_path: PathBuf,
+		storage: Arc<FileStorage>,
 	) -> Self {
 		// we don't need the cert
 		config.certificate.take();
@@ -80,6 +97,8 @@ impl KConnectHandler {
 			// this should never fail
 			verification_key: verification_key.try_into().unwrap(),
 			documents_path,
+			storage,
+			shared_files: Vec::new(),
 		}
 	}
 }
@@ -129,27 +148,62 @@ impl KConnectHandler {
 
 #[async_trait]
 impl DeviceHandler for KConnectHandler {
-	async fn handle_ping(&mut self, packet: Ping) {
+	async fn handle_pair_status_change(&mut self, pair_status: bool) {
 		info!(
-			"recieved ping: {:?} packet: {:#?}",
-			self.config.name, packet
+			"device {}: {:?}",
+			if pair_status { "paired" } else { "unpaired" },
+			self.config.name
 		);
 
 		let id = self.id.clone();
-		call_callback_no_ret!(ping_recieved, id);
+		call_callback_no_ret!(pair_status_changed, id, pair_status)
 	}
 
-	async fn handle_pair_status_change(&mut self, pair_status: bool) {
+	async fn handle_pairing_request(&mut self) -> bool {
+		info!("recieved pair from {:?}", self.config);
+		let id = self.id.clone();
+		let key = self.verification_key.clone();
+		let res = call_callback!(pairing_requested, id, key).unwrap_or(false);
+
 		info!(
-			"device {}: {:?}",
-			if pair_status { "paired" } else { "unpaired" },
+			"pair {} from {:?}",
+			if res { "accepted" } else { "rejected" },
 			self.config.name
 		);
+		res
+	}
+
+	async fn handle_unknown_packet(&mut self, packet: Packet) {
+		warn!(
+			"unknown packet from {:?}: {:?}",
+			self.config.name, packet.packet_type
+		);
+	}
 
+	async fn handle_exit(&mut self) {
+		if let Some(state) = STATE.lock().await.as_mut() {
+			state.devices.retain(|x| x.config.id != self.config.id);
+		}
 		let id = self.id.clone();
-		call_callback_no_ret!(pair_status_changed, id, pair_status)
+		call_callback_no_ret!(gone, id);
+	}
+}
+
+#[async_trait]
+impl PingHandler for KConnectHandler {
+	async fn handle_ping(&mut self, packet: Ping) {
+		info!(
+			"recieved ping: {:?} packet: {:#?}",
+			self.config.name, packet
+		);
+
+		let id = self.id.clone();
+		call_callback_no_ret!(ping_recieved, id);
 	}
+}
 
+#[async_trait]
+impl BatteryHandler for KConnectHandler {
 	async fn handle_battery(&mut self, packet: Battery) {
 		let mut state = self.state.lock().await;
 		state.battery.replace(packet);
@@ -164,6 +218,17 @@ impl DeviceHandler for KConnectHandler {
 		call_callback_no_ret!(battery_changed, id);
 	}
 
+	async fn get_battery(&mut self) -> Battery {
+		STATE
+			.lock()
+			.await
+			.as_ref()
+			.map_or(UNKNOWN_BATTERY, |x| x.current_battery)
+	}
+}
+
+#[async_trait]
+impl ClipboardHandler for KConnectHandler {
 	async fn handle_clipboard_content(&mut self, content: String) {
 		self.state.lock().await.clipboard.replace(content.clone());
 
@@ -173,16 +238,30 @@ impl DeviceHandler for KConnectHandler {
 		call_callback_no_ret!(clipboard_changed, id, content);
 	}
 
+	async fn get_clipboard_content(&mut self) -> String {
+		STATE
+			.lock()
+			.await
+			.as_ref()
+			.map(|x| x.current_clipboard.clone())
+			.unwrap_or_default()
+	}
+}
+
+#[async_trait]
+impl FindPhoneHandler for KConnectHandler {
 	async fn handle_find_phone(&mut self) {
-		// STATE will always be Some here
-		let mut locked = STATE.lock().await;
-		let state = locked.as_mut().unwrap();
-		state.being_found = !state.being_found;
-		if state.being_found {
-			call_callback_no_ret!(find_requested,);
+		if let Some(state) = STATE.lock().await.as_mut() {
+			state.being_found = !state.being_found;
+			if state.being_found {
+				call_callback_no_ret!(find_requested,);
+			}
 		}
 	}
+}
 
+#[async_trait]
+impl ConnectivityReportHandler for KConnectHandler {
 	async fn handle_connectivity_report(&mut self, packet: ConnectivityReport) {
 		self.state.lock().await.connectivity.replace(packet);
 
@@ -190,10 +269,27 @@ impl DeviceHandler for KConnectHandler {
 		call_callback_no_ret!(connectivity_changed, id)
 	}
 
+	async fn get_connectivity_report(&mut self) -> ConnectivityReport {
+		ConnectivityReport {
+			signal_strengths: STATE
+				.lock()
+				.await
+				.as_ref()
+				.map(|x| x.current_signals.clone())
+				.unwrap_or_default(),
+		}
+	}
+}
+
+#[async_trait]
+impl PresenterHandler for KConnectHandler {
 	async fn handle_presenter(&mut self, _packet: Presenter) {
 		// Ignore - not much use on iOS
 	}
+}
 
+#[async_trait]
+impl SystemVolumeHandler for KConnectHandler {
 	async fn handle_system_volume(&mut self, packet: SystemVolume) {
 		info!("system volume: {:?}", packet);
 		match packet {
@@ -238,13 +334,28 @@ impl DeviceHandler for KConnectHandler {
 			if muted {
 				call_callback_no_ret!(volume_change_requested, 0)
 			} else {
-				// STATE is always Some here
-				let vol = STATE.lock().await.as_ref().unwrap().current_volume;
+				let vol = STATE.lock().await.as_ref().map_or(0, |x| x.current_volume);
 				call_callback_no_ret!(volume_change_requested, vol);
 			}
 		}
 	}
 
+	async fn get_system_volume(&mut self) -> Vec<SystemVolumeStream> {
+		let vol = STATE.lock().await.as_ref().map_or(0, |x| x.current_volume);
+
+		vec![SystemVolumeStream {
+			name: "coreaudio".to_string(),
+			description: "Core Audio".to_string(),
+			muted: vol == 0,
+			volume: vol,
+			max_volume: Some(100),
+			enabled: None,
+		}]
+	}
+}
+
+#[async_trait]
+impl ShareHandler for KConnectHandler {
 	async fn handle_multi_file_share(&mut self, _packet: ShareRequestUpdate) {
 		// ignore
 	}
@@ -252,48 +363,48 @@ impl DeviceHandler for KConnectHandler {
 	async fn handle_file_share(
 		&mut self,
 		packet: ShareRequestFile,
-		_size: i64,
-		mut data: Pin<Box<dyn AsyncRead + Sync + Send>>,
-	) {
+		size: i64,
+		data: Pin<Box<dyn AsyncRead + Sync + Send>>,
+		_transfer: TransferHandle,
+		_session: &ShareSession,
+	) -> Result<(), KdeConnectError> {
 		let ret = async {
-			let current_time = SystemTime::now()
-				.duration_since(SystemTime::UNIX_EPOCH)
-				.expect("time went backwards")
-				.as_millis();
-			let mut path = self.documents_path.join(packet.filename);
-			if tokio::fs::try_exists(&path).await? {
-				let current_name = path
-					.file_name()
-					.unwrap_or(OsStr::new(""))
-					.to_os_string()
-					.into_string()
-					.map_err(|_| KdeConnectError::OsStringConversionError)?;
-				path.set_file_name(current_time.to_string() + &current_name);
-			}
-			let mut file = File::create(&path).await?;
-			// kdeconnect-kde is weird sometimes and closes without properly closing TLS
-			let _ = tokio::io::copy(&mut data, &mut file).await;
-			file.shutdown().await?;
-			file.sync_all().await?;
-			Ok::<String, Box<dyn Error + Sync + Send>>(
-				path.into_os_string()
-					.into_string()
-					.map_err(|_| KdeConnectError::OsStringConversionError)?,
-			)
+			let path = self
+				.storage
+				.store(&self.config.id, &packet, size, data)
+				.await?;
+			path.into_os_string()
+				.into_string()
+				.map_err(|_| KdeConnectError::OsStringConversionError)
 		}
 		.await;
 
 		match ret {
 			Ok(path) => {
-				// this should never fail
-				call_callback_no_ret!(open_file, path.try_into().unwrap());
+				self.shared_files.push(path);
+				Ok(())
 			}
 			Err(err) => {
 				error!("failed to save file from share: {:?}", err);
+				Err(err)
 			}
 		}
 	}
 
+	async fn handle_share_session_finished(&mut self, session: ShareSession) {
+		let paths = std::mem::take(&mut self.shared_files);
+		let id = self.id.clone();
+		if session.number_of_files().await == 1 && paths.len() == 1 {
+			// this should never fail
+			let path: char_p::Box = paths[0].clone().try_into().unwrap();
+			call_callback_no_ret!(open_file, path);
+		} else if !paths.is_empty() {
+			// this should never fail
+			let paths: char_p::Box = paths.join("\n").try_into().unwrap();
+			call_callback_no_ret!(files_shared, id, paths);
+		}
+	}
+
 	async fn handle_url_share(&mut self, url: String) {
 		// this should never fail
 		call_callback_no_ret!(open_url, url.try_into().unwrap());
@@ -303,7 +414,10 @@ impl DeviceHandler for KConnectHandler {
 		// this should never fail
 		call_callback_no_ret!(open_text, text.try_into().unwrap());
 	}
+}
 
+#[async_trait]
+impl MprisHandler for KConnectHandler {
 	async fn handle_mpris_player_list(&mut self, players: Vec<String>) {
 		info!("got player list {:?}", players);
 		self.state
@@ -423,8 +537,9 @@ impl DeviceHandler for KConnectHandler {
 				}
 				path.push(album_file_name);
 				let mut file = File::create(&path).await?;
-				// kdeconnect-kde is weird sometimes and closes without properly closing TLS
-				let _ = tokio::io::copy(&mut data, &mut file).await;
+				// kdeconnect-kde closing without a close_notify is already tolerated by the reader,
+				// anything else means the art is incomplete
+				tokio::io::copy(&mut data, &mut file).await?;
 				file.shutdown().await?;
 				file.sync_all().await?;
 				path.into_os_string()
@@ -482,88 +597,18 @@ impl DeviceHandler for KConnectHandler {
 		}
 	}
 
-	// Ignore as there is not much use
-	async fn handle_mousepad_request(&mut self, _: MousepadRequest) {}
-	async fn handle_mousepad_keyboard_state(&mut self, _: MousepadKeyboardState) {}
-	async fn handle_mousepad_echo(&mut self, _: MousepadEcho) {}
-
-	async fn handle_command_list(&mut self, command_list: HashMap<String, RunCommandItem>) {
-		self.state.lock().await.commands = command_list;
-		let id = self.id.clone();
-		call_callback_no_ret!(commands_changed, id);
-	}
-
-	async fn handle_command_request(&mut self, _: String) {
-		// TODO
-	}
-
-	async fn handle_pairing_request(&mut self) -> bool {
-		info!("recieved pair from {:?}", self.config);
-		let id = self.id.clone();
-		let key = self.verification_key.clone();
-		let res = call_callback!(pairing_requested, id, key).unwrap_or(false);
-
-		info!(
-			"pair {} from {:?}",
-			if res { "accepted" } else { "rejected" },
-			self.config.name
-		);
-		res
-	}
-
-	async fn get_battery(&mut self) -> Battery {
-		// STATE will always be Some here
-		STATE.lock().await.as_ref().unwrap().current_battery
-	}
-
-	async fn get_clipboard_content(&mut self) -> String {
-		// STATE will always be Some here
-		STATE
-			.lock()
-			.await
-			.as_ref()
-			.unwrap()
-			.current_clipboard
-			.clone()
-	}
-
-	async fn get_connectivity_report(&mut self) -> ConnectivityReport {
-		// STATE will always be Some here
-		ConnectivityReport {
-			signal_strengths: STATE.lock().await.as_ref().unwrap().current_signals.clone(),
-		}
-	}
-
-	async fn get_system_volume(&mut self) -> Vec<SystemVolumeStream> {
-		// STATE will always be Some here
-		let vol = STATE.lock().await.as_ref().unwrap().current_volume;
-
-		vec![SystemVolumeStream {
-			name: "coreaudio".to_string(),
-			description: "Core Audio".to_string(),
-			muted: vol == 0,
-			volume: vol,
-			max_volume: Some(100),
-			enabled: None,
-		}]
-	}
-
 	async fn get_mpris_player_list(&mut self) -> Vec<String> {
-		// STATE will always be Some here
 		STATE
 			.lock()
 			.await
 			.as_ref()
-			.unwrap()
-			.current_player
-			.as_ref()
+			.and_then(|x| x.current_player.as_ref())
 			.map_or_else(Vec::new, |x| vec![x.player.clone()])
 	}
 
 	async fn get_mpris_player(&mut self, player: String) -> Option<MprisPlayer> {
 		let locked = STATE.lock().await;
-		// STATE will always be Some here
-		if let Some(current_player) = locked.as_ref().unwrap().current_player.as_ref()
+		if let Some(current_player) = locked.as_ref().and_then(|x| x.current_player.as_ref())
 			&& current_player.player == player
 		{
 			Some(current_player.clone())
@@ -571,25 +616,35 @@ impl DeviceHandler for KConnectHandler {
 			None
 		}
 	}
+}
 
-	async fn handle_exit(&mut self) {
-		// STATE will always be Some here
-		STATE
-			.lock()
-			.await
-			.as_mut()
-			.unwrap()
-			.devices
-			.retain(|x| x.config.id != self.config.id);
+#[async_trait]
+impl MousepadHandler for KConnectHandler {
+	// Ignore as there is not much use
+	async fn handle_mousepad_request(&mut self, _: MousepadRequest) {}
+	async fn handle_mousepad_keyboard_state(&mut self, _: MousepadKeyboardState) {}
+	async fn handle_mousepad_echo(&mut self, _: MousepadEcho) {}
+}
+
+#[async_trait]
+impl RunCommandHandler for KConnectHandler {
+	async fn handle_command_list(&mut self, command_list: HashMap<String, RunCommandItem>) {
+		self.state.lock().await.commands = command_list;
 		let id = self.id.clone();
-		call_callback_no_ret!(gone, id);
+		call_callback_no_ret!(commands_changed, id);
 	}
 
-	async fn get_command_list(&mut self) -> HashMap<String, RunCommandItem> {
-		// TODO
-		HashMap::new()
+	async fn handle_command_output(
+		&mut self,
+		command_id: String,
+		output: Result<CommandOutput, KdeConnectError>,
+	) {
+		info!("ran command {:?}: {:?}", command_id, output);
 	}
+}
 
+#[async_trait]
+impl TelephonyHandler for KConnectHandler {
 	// TODO
 	async fn handle_telephony(&mut self, _packet: Telephony) {}
 	async fn handle_telephony_mute_request(&mut self) {}
@@ -8,6 +8,7 @@ use std::{
 	collections::HashMap,
 	error::Error,
 	io,
+	net::{IpAddr, SocketAddr},
 	path::PathBuf,
 	sync::{Arc, OnceLock},
 };
@@ -20,17 +21,24 @@ use device::{
 	KConnectTelephonyEvent, KConnectVolumeStream,
 };
 use kdeconnect::{
-	config::FsConfig,
+	commands::CommandRegistry,
+	config::{ConfigProvider, FsConfig, FsConfigNames},
 	device::DeviceFile,
 	packets::{
-		Battery, BatteryRequest, Clipboard, ClipboardConnect, ConnectivityReport,
-		ConnectivityReportNetworkType, ConnectivityReportRequest, ConnectivityReportSignal,
-		FindPhone, MousepadEcho, MousepadKeyboardState, MousepadRequest, MousepadSpecialKey, Mpris,
-		MprisAction, MprisLoopStatus, MprisPlayer, MprisRequest, MprisRequestAction, Ping,
-		Presenter, RunCommand, RunCommandRequest, ShareRequest, SystemVolume, SystemVolumeRequest,
-		Telephony, TelephonyRequestMute,
+		Battery, ConnectivityReport, ConnectivityReportNetworkType, ConnectivityReportSignal,
+		MousepadEcho, MousepadKeyboardState, MousepadRequest, MousepadSpecialKey, MprisAction,
+		MprisLoopStatus, MprisPlayer, MprisRequestAction, Presenter, Telephony,
+		TelephonyRequestMute,
 	},
-	KdeConnect, KdeConnectClient, KdeConnectError,
+	plugins::{
+		battery::BatteryPlugin, clipboard::ClipboardPlugin,
+		connectivity_report::ConnectivityReportPlugin, findmyphone::FindPhonePlugin,
+		mousepad::MousepadPlugin, mpris::MprisPlugin, ping::PingPlugin, presenter::PresenterPlugin,
+		runcommand::RunCommandPlugin, share::SharePlugin, systemvolume::SystemVolumePlugin,
+		telephony::TelephonyPlugin, PluginRegistry,
+	},
+	storage::FileStorage,
+	KdeConnect, KdeConnectClient, KdeConnectError, KDECONNECT_PORT,
 };
 use log::info;
 #[cfg(target_os = "ios")]
@@ -40,17 +48,26 @@ use oslog::OsLogger;
 use safer_ffi::{boxed::Box_, ffi_export, prelude::*};
 #[cfg(target_os = "ios")]
 use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, TerminalMode};
-use tokio::{runtime::Runtime, sync::Mutex};
+use tokio::{runtime::Runtime, sync::Mutex, task::JoinHandle};
 use tokio_stream::StreamExt;
 
 static RUNTIME: OnceLock<Runtime> = OnceLock::new();
 static STATE: Mutex<Option<KConnectState>> = Mutex::const_new(None);
 static CALLBACKS: Mutex<KConnectCallbacks> = Mutex::const_new(KConnectCallbacks::new());
 
+const UNKNOWN_BATTERY: Battery = Battery {
+	charge: -1,
+	is_charging: false,
+	under_threshold: false,
+};
+
 struct KConnectState {
 	client: KdeConnectClient,
 	config: Arc<FsConfig>,
+	commands: Arc<CommandRegistry>,
 	devices: Vec<KConnectDevice>,
+	// device tasks, waited for on shutdown so they can clean up
+	tasks: Vec<JoinHandle<()>>,
 	current_battery: Battery,
 	current_clipboard: String,
 	current_signals: HashMap<String, ConnectivityReportSignal>,
@@ -60,16 +77,18 @@ struct KConnectState {
 }
 
 impl KConnectState {
-	pub fn new(client: KdeConnectClient, config: Arc<FsConfig>) -> Self {
+	pub fn new(
+		client: KdeConnectClient,
+		config: Arc<FsConfig>,
+		commands: Arc<CommandRegistry>,
+	) -> Self {
 		Self {
 			client,
 			config,
+			commands,
 			devices: Vec::new(),
-			current_battery: Battery {
-				charge: -1,
-				is_charging: false,
-				under_threshold: false,
-			},
+			tasks: Vec::new(),
+			current_battery: UNKNOWN_BATTERY,
 			current_clipboard: String::new(),
 			current_signals: HashMap::new(),
 			current_volume: 0,
@@ -117,66 +136,59 @@ pub extern "C" fn kdeconnect_start(
 			}
 
 			let config_provider = Arc::new(
-				FsConfig::new(
-					config_path.to_string().into(),
-					"server_cert".into(),
-					"server_keypair".into(),
-					"devices".into(),
-				)
-				.await?,
+				FsConfig::new(config_path.to_string().into(), FsConfigNames::default()).await?,
 			);
+			let command_registry = Arc::new(CommandRegistry::new(config_provider.clone()).await?);
+			let storage = Arc::new(FileStorage::new(documents_path.clone()));
+
+			let mut plugins = PluginRegistry::<KConnectHandler>::new();
+			let registry = command_registry.clone();
+			plugins
+				.register(|x| PingPlugin::new(x.clone()))
+				.register(|x| BatteryPlugin::new(x.clone()))
+				.register(|x| ClipboardPlugin::new(x.clone()))
+				.register(|x| FindPhonePlugin::new(x.clone()))
+				.register(|x| ConnectivityReportPlugin::new(x.clone()))
+				.register(|x| SystemVolumePlugin::new(x.clone()))
+				.register(|x| SharePlugin::new(x.clone()))
+				.register(|x| MprisPlugin::new(x.clone()))
+				.register(move |x| RunCommandPlugin::new(x.clone(), registry.clone()))
+				// presenter and mousepad requests are only sent
+				.register_with_capabilities(vec![], vec![Presenter::TYPE.to_string()], |x| {
+					PresenterPlugin::new(x.clone())
+				})
+				.register_with_capabilities(
+					vec![
+						MousepadEcho::TYPE.to_string(),
+						MousepadKeyboardState::TYPE.to_string(),
+					],
+					vec![
+						MousepadRequest::TYPE.to_string(),
+						MousepadEcho::TYPE.to_string(),
+						MousepadKeyboardState::TYPE.to_string(),
+					],
+					|x| MousepadPlugin::new(x.clone()),
+				)
+				.register_with_capabilities(
+					vec![TelephonyRequestMute::TYPE.to_string()],
+					vec![Telephony::TYPE.to_string()],
+					|x| TelephonyPlugin::new(x.clone()),
+				);
+
 			let (kdeconnect, client, mut device_stream) = KdeConnect::new(
 				device_id.to_string(),
 				device_name.to_string(),
 				device_type.into(),
-				vec![
-					Ping::TYPE.to_string(),
-					Battery::TYPE.to_string(),
-					BatteryRequest::TYPE.to_string(),
-					Clipboard::TYPE.to_string(),
-					ClipboardConnect::TYPE.to_string(),
-					FindPhone::TYPE.to_string(),
-					ConnectivityReport::TYPE.to_string(),
-					ConnectivityReportRequest::TYPE.to_string(),
-					SystemVolume::TYPE.to_string(),
-					SystemVolumeRequest::TYPE.to_string(),
-					ShareRequest::TYPE.to_string(),
-					Mpris::TYPE.to_string(),
-					MprisRequest::TYPE.to_string(),
-					MousepadEcho::TYPE.to_string(),
-					MousepadKeyboardState::TYPE.to_string(),
-					TelephonyRequestMute::TYPE.to_string(),
-				],
-				vec![
-					Ping::TYPE.to_string(),
-					Battery::TYPE.to_string(),
-					BatteryRequest::TYPE.to_string(),
-					Clipboard::TYPE.to_string(),
-					ClipboardConnect::TYPE.to_string(),
-					FindPhone::TYPE.to_string(),
-					ConnectivityReport::TYPE.to_string(),
-					ConnectivityReportRequest::TYPE.to_string(),
-					Presenter::TYPE.to_string(),
-					SystemVolume::TYPE.to_string(),
-					SystemVolumeRequest::TYPE.to_string(),
-					ShareRequest::TYPE.to_string(),
-					Mpris::TYPE.to_string(),
-					MprisRequest::TYPE.to_string(),
-					MousepadRequest::TYPE.to_string(),
-					MousepadEcho::TYPE.to_string(),
-					MousepadKeyboardState::TYPE.to_string(),
-					RunCommand::TYPE.to_string(),
-					RunCommandRequest::TYPE.to_string(),
-					Telephony::TYPE.to_string(),
-				],
+				&plugins,
 				config_provider.clone(),
 			)
 			.await?;
 
-			STATE
-				.lock()
-				.await
-				.replace(KConnectState::new(client, config_provider));
+			STATE.lock().await.replace(KConnectState::new(
+				client,
+				config_provider,
+				command_registry,
+			));
 
 			info!("created kdeconnect client");
 
@@ -201,34 +213,36 @@ pub extern "C" fn kdeconnect_start(
 				let client = Arc::new(client);
 				let config = dev.config.clone();
 
-				#[allow(clippy::redundant_closure)]
-				let handler = Box::new(KConnectHandler::new(
+				let handler = KConnectHandler::new(
 					state.clone(),
 					client.clone(),
 					dev.config.clone(),
 					key,
 					documents_path.clone(),
-				));
+					storage.clone(),
+				);
+				let device_plugins = plugins.create_plugins(&handler);
 
 				// this should never fail
 				let id = dev.config.id.clone().try_into().unwrap();
 
-				tokio::spawn(async move {
-					info!("handler task exited: {:?}", dev.task(handler).await);
+				let task = tokio::spawn(async move {
+					info!(
+						"handler task exited: {:?}",
+						dev.task(Box::new(handler), device_plugins).await
+					);
 				});
 
-				// STATE will always be Some
-				STATE
-					.lock()
-					.await
-					.as_mut()
-					.unwrap()
-					.devices
-					.push(KConnectDevice {
+				// STATE is only None after kdeconnect_shutdown
+				if let Some(state_ref) = STATE.lock().await.as_mut() {
+					state_ref.devices.push(KConnectDevice {
 						client,
 						state,
 						config,
 					});
+					state_ref.tasks.retain(|x| !x.is_finished());
+					state_ref.tasks.push(task);
+				}
 
 				call_callback_no_ret!(discovered, id);
 			}
@@ -243,6 +257,51 @@ pub extern "C" fn kdeconnect_start(
 	}
 }
 
+// kdeconnect_start returns once the server stopped and can be called again, for example with a
+// new name
+#[ffi_export]
+pub extern "C" fn kdeconnect_shutdown() -> bool {
+	if let Ok(rt) = build_runtime!() {
+		rt.block_on(async {
+			let tasks = {
+				let mut locked = STATE.lock().await;
+				let state = locked.as_mut().ok_or(KdeConnectError::Other)?;
+				state.client.shutdown().await?;
+				std::mem::take(&mut state.tasks)
+			};
+			// the tasks lock STATE when they exit so it can't be held here
+			for task in tasks {
+				let _ = task.await;
+			}
+			STATE.lock().await.take();
+			Ok::<(), KdeConnectError>(())
+		})
+		.is_ok()
+	} else {
+		false
+	}
+}
+
+#[ffi_export]
+pub extern "C" fn kdeconnect_rename(device_name: char_p::Ref<'_>) -> bool {
+	let device_name = device_name.to_string();
+	if let Ok(rt) = build_runtime!() {
+		rt.block_on(async {
+			STATE
+				.lock()
+				.await
+				.as_ref()
+				.ok_or(KdeConnectError::Other)?
+				.client
+				.rename(device_name)
+				.await
+		})
+		.is_ok()
+	} else {
+		false
+	}
+}
+
 #[ffi_export]
 pub extern "C" fn kdeconnect_free_string(str: char_p::Box) {
 	drop(str)
@@ -267,6 +326,113 @@ pub extern "C" fn kdeconnect_broadcast_identity() -> bool {
 	}
 }
 
+// the port can be left out
+fn parse_device_addr(addr: char_p::Ref<'_>) -> Option<SocketAddr> {
+	let addr = addr.to_str();
+	addr.parse().ok().or_else(|| {
+		addr.parse::<IpAddr>()
+			.ok()
+			.map(|x| SocketAddr::new(x, KDECONNECT_PORT))
+	})
+}
+
+#[ffi_export]
+pub extern "C" fn kdeconnect_connect_to(addr: char_p::Ref<'_>) -> bool {
+	let Some(addr) = parse_device_addr(addr) else {
+		return false;
+	};
+	if let Ok(rt) = build_runtime!() {
+		rt.block_on(async {
+			STATE
+				.lock()
+				.await
+				.as_ref()
+				.ok_or(KdeConnectError::Other)?
+				.client
+				.connect_to(addr)
+				.await
+		})
+		.is_ok()
+	} else {
+		false
+	}
+}
+
+#[ffi_export]
+pub extern "C" fn kdeconnect_get_custom_devices() -> repr_c::Vec<char_p::Box> {
+	if let Ok(rt) = build_runtime!() {
+		rt.block_on(async {
+			let devices = STATE
+				.lock()
+				.await
+				.as_ref()
+				.ok_or(KdeConnectError::Other)?
+				.client
+				.custom_devices()
+				.await?;
+			Ok::<Vec<_>, KdeConnectError>(
+				devices
+					.into_iter()
+					.map(|x| x.to_string().try_into().unwrap())
+					.collect(),
+			)
+		})
+		.unwrap_or_default()
+		.into()
+	} else {
+		vec![].into()
+	}
+}
+
+#[ffi_export]
+pub extern "C" fn kdeconnect_free_custom_devices(devices: repr_c::Vec<char_p::Box>) {
+	drop(devices);
+}
+
+#[ffi_export]
+pub extern "C" fn kdeconnect_add_custom_device(addr: char_p::Ref<'_>) -> bool {
+	let Some(addr) = parse_device_addr(addr) else {
+		return false;
+	};
+	if let Ok(rt) = build_runtime!() {
+		rt.block_on(async {
+			STATE
+				.lock()
+				.await
+				.as_ref()
+				.ok_or(KdeConnectError::Other)?
+				.client
+				.add_custom_device(addr)
+				.await
+		})
+		.is_ok()
+	} else {
+		false
+	}
+}
+
+#[ffi_export]
+pub extern "C" fn kdeconnect_remove_custom_device(addr: char_p::Ref<'_>) -> bool {
+	let Some(addr) = parse_device_addr(addr) else {
+		return false;
+	};
+	if let Ok(rt) = build_runtime!() {
+		rt.block_on(async {
+			STATE
+				.lock()
+				.await
+				.as_ref()
+				.ok_or(KdeConnectError::Other)?
+				.client
+				.remove_custom_device(addr)
+				.await
+		})
+		.is_ok()
+	} else {
+		false
+	}
+}
+
 #[ffi_export]
 pub extern "C" fn kdeconnect_get_is_lost() -> bool {
 	if let Ok(rt) = build_runtime!() {
@@ -1080,6 +1246,81 @@ pub extern "C" fn kdeconnect_device_run_command(
 	}
 }
 
+#[ffi_export]
+pub extern "C" fn kdeconnect_get_local_commands() -> repr_c::Vec<KConnectCommand> {
+	if let Ok(rt) = build_runtime!() {
+		rt.block_on(async {
+			let registry = STATE
+				.lock()
+				.await
+				.as_ref()
+				.ok_or(KdeConnectError::Other)?
+				.commands
+				.clone();
+
+			let mut out = Vec::new();
+
+			for command in registry.list().await {
+				out.push(KConnectCommand {
+					id: command.0.try_into().unwrap(),
+					name: command.1.name.try_into().unwrap(),
+					command: command.1.command.try_into().unwrap(),
+				});
+			}
+
+			Ok::<Vec<_>, KdeConnectError>(out)
+		})
+		.unwrap_or_default()
+		.into()
+	} else {
+		vec![].into()
+	}
+}
+
+#[ffi_export]
+pub extern "C" fn kdeconnect_add_local_command(
+	name: char_p::Ref<'_>,
+	command: char_p::Ref<'_>,
+) -> bool {
+	let name = name.to_string();
+	let command = command.to_string();
+	if let Ok(rt) = build_runtime!() {
+		rt.block_on(async {
+			STATE
+				.lock()
+				.await
+				.as_ref()
+				.ok_or(KdeConnectError::Other)?
+				.commands
+				.add(name, command)
+				.await
+		})
+		.is_ok()
+	} else {
+		false
+	}
+}
+
+#[ffi_export]
+pub extern "C" fn kdeconnect_remove_local_command(id: char_p::Ref<'_>) -> bool {
+	let id = id.to_string();
+	if let Ok(rt) = build_runtime!() {
+		rt.block_on(async {
+			STATE
+				.lock()
+				.await
+				.as_ref()
+				.ok_or(KdeConnectError::Other)?
+				.commands
+				.remove(&id)
+				.await
+		})
+		.is_ok()
+	} else {
+		false
+	}
+}
+
 #[ffi_export]
 pub extern "C" fn kdeconnect_on_battery_event(
 	level: i32,